@@ -0,0 +1,102 @@
+// Commands a server operator types on the console of a running server, one per line.
+
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+pub const HELP: &str = "\
+list               clients with their tokens, addresses and what they do
+kick <token|name>  disconnects a client, its player is gone for good
+say <text>         shows the text to everyone
+map <name>         changes the level, everyone spawns again
+shutdown           tells everyone the server is going away and stops it
+help               this text";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    List,
+    // a token or the name of a player or lobby member
+    Kick(String),
+    Say(String),
+    Map(String),
+    Shutdown,
+    Help
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let line = line.trim();
+        let (name, arg) = match line.find(char::is_whitespace) {
+            Some(idx) => (&line[..idx], line[idx..].trim()),
+            None => (line, "")
+        };
+
+        let command = match name {
+            "list" => Command::List,
+            "kick" => Command::Kick(arg.to_string()),
+            "say" => Command::Say(arg.to_string()),
+            "map" => Command::Map(arg.to_string()),
+            "shutdown" => Command::Shutdown,
+            "help" => Command::Help,
+            _ => return Err(format!("unknown command `{}`, type `help` for the list", name))
+        };
+
+        match command {
+            Command::Kick(ref arg) | Command::Say(ref arg) | Command::Map(ref arg) if arg.is_empty() => {
+                Err(format!("`{}` needs an argument, type `help` for the list", name))
+            },
+            command => Ok(command)
+        }
+    }
+}
+
+// commands typed on stdin, bad lines are answered right away;
+// the channel closes with stdin, a server without a terminal keeps running
+pub fn read_stdin() -> Receiver<Command> {
+    let (commands, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let stdin = io::stdin();
+
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match Command::parse(&line) {
+                Ok(command) => if commands.send(command).is_err() {
+                    break;
+                },
+                Err(err) => println!("{}", err)
+            }
+        }
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(Command::parse("list"), Ok(Command::List));
+        assert_eq!(Command::parse("  kick 3 "), Ok(Command::Kick("3".to_string())));
+        assert_eq!(Command::parse("say hello  there"), Ok(Command::Say("hello  there".to_string())));
+        assert_eq!(Command::parse("map arena"), Ok(Command::Map("arena".to_string())));
+        assert_eq!(Command::parse("shutdown"), Ok(Command::Shutdown));
+    }
+
+    #[test]
+    fn bad_commands_are_rejected() {
+        assert!(Command::parse("teleport 1").is_err());
+        assert!(Command::parse("kick").is_err());
+        assert!(Command::parse("say   ").is_err());
+    }
+}
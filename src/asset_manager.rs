@@ -1,13 +1,23 @@
 use std::collections::HashMap;
-use piston_window::G2dTexture;
+use piston_window::{G2dTexture, Glyphs};
 
 pub struct AssetManager {
-    textures: HashMap<String, G2dTexture>
+    textures: HashMap<String, G2dTexture>,
+    // font for text drawn outside of conrod ui
+    glyphs: Option<Glyphs>
 }
 
 impl AssetManager {
     pub fn new() -> AssetManager {
-        AssetManager { textures: HashMap::new() }
+        AssetManager { textures: HashMap::new(), glyphs: None }
+    }
+
+    pub fn set_glyphs(&mut self, glyphs: Glyphs) {
+        self.glyphs = Some(glyphs);
+    }
+
+    pub fn get_glyphs_mut(&mut self) -> Option<&mut Glyphs> {
+        self.glyphs.as_mut()
     }
 
     pub fn add_texture(&mut self, key: &'static str, texture: G2dTexture) {
@@ -15,10 +25,10 @@ impl AssetManager {
     }
 
     pub fn get_texture(&self, name: &'static str) -> Option<&G2dTexture> {
-        self.textures.get(&name.to_string())
+        self.textures.get(name)
     }
 
     pub fn get_texture_mut(&mut self, name: &'static str) -> Option<&mut G2dTexture> {
-        self.textures.get_mut(&name.to_string())
+        self.textures.get_mut(name)
     }
 }
\ No newline at end of file
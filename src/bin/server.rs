@@ -0,0 +1,29 @@
+extern crate side_run;
+
+use std::env;
+use std::process;
+use side_run::admin;
+use side_run::server::Server;
+
+const DEFAULT_ADDR: &str = "0.0.0.0:7001";
+
+pub fn main() {
+    let addr = env::args().nth(1).unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    let name = env::args().nth(2);
+
+    let mut server = match Server::bind(&addr[..]) {
+        Ok(server) => server,
+        Err(err) => {
+            println!("Failed to bind {}: {}", addr, err);
+            process::exit(1);
+        }
+    };
+
+    if let Some(name) = name {
+        server.set_name(name);
+    }
+
+    println!("side-run server is listening on {}, type `help` for admin commands", addr);
+
+    server.run(admin::read_stdin());
+}
@@ -1,139 +1,577 @@
-use std::net::TcpStream;
-use byteorder::{ByteOrder, BigEndian};
-use std::time::Duration;
-use scenes::common::*;
-use std::io::{Read, Write};
-use piston_window::types::Color;
-use piston_window::math::Vec2d;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::io::{ErrorKind, Read, Write};
+use std::mem;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use protocol::{self, Channel, FrameBuffer, Message, ProtocolError, Sequencer, Color, Vec2d, MAX_DATAGRAM_LEN};
+use handshake::{self, Hello, FEATURE_UDP, HANDSHAKE_TIMEOUT};
+use heartbeat::Heartbeat;
+use netsim::{Lane, NetConditions};
 
-pub type NetToken = usize;
+pub use protocol::NetToken;
+
+// how often a blocking resume checks for the answer
+const RESUME_POLL_INTERVAL: Duration = Duration::from_millis(5);
+// empty datagrams are sent this often until the server answers one
+const UDP_PROBE_INTERVAL: Duration = Duration::from_millis(250);
+// unanswered probes before udp is given up as filtered on the way
+const UDP_PROBES: u32 = 12;
+
+// Non-blocking stream of framed messages over a tcp socket,
+// used on both sides of the connection.
+pub struct MessageStream {
+    socket: TcpStream,
+    recv_buf: FrameBuffer,
+    // encoded messages the socket did not accept yet
+    send_buf: Vec<u8>
+}
+
+impl MessageStream {
+    pub fn new(socket: TcpStream) -> Result<MessageStream, ProtocolError> {
+        // messages are polled every tick, so reads must never block the game loop
+        socket.set_nonblocking(true)?;
+
+        Ok(MessageStream {
+            socket,
+            recv_buf: FrameBuffer::new(),
+            send_buf: Vec::new()
+        })
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    // reads everything the socket has for now and returns all completely received messages,
+    // a fatal error (if any) is always the last item
+    pub fn poll_messages(&mut self) -> Vec<Result<Message, ProtocolError>> {
+        let mut buf = [0u8; 512];
+        let mut stream_error = self.flush().err();
+
+        while stream_error.is_none() {
+            match self.socket.read(&mut buf) {
+                Ok(0) => stream_error = Some(ProtocolError::Disconnected),
+                Ok(len) => self.recv_buf.extend(&buf[..len]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => stream_error = Some(e.into())
+            }
+        }
+
+        let mut results = vec![];
+
+        while let Some(result) = self.recv_buf.next_message() {
+            results.push(result);
+        }
+
+        if let Some(err) = stream_error {
+            results.push(Err(err));
+        }
+
+        results
+    }
+
+    pub fn send(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        self.send_buf.extend(protocol::encode(message));
+
+        self.flush()
+    }
+
+    // writes as much of the pending output as the socket accepts without blocking
+    pub fn flush(&mut self) -> Result<(), ProtocolError> {
+        while !self.send_buf.is_empty() {
+            match self.socket.write(&self.send_buf) {
+                Ok(0) => return Err(ProtocolError::Disconnected),
+                Ok(len) => {
+                    self.send_buf.drain(..len);
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into())
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Client side of the udp channel to the server, carries unreliable messages only.
+struct DatagramChannel {
+    socket: UdpSocket,
+    token: NetToken,
+    sequencer: Sequencer,
+    // set when a datagram from the server arrived, so they get through both ways
+    confirmed: bool,
+    probes: u32,
+    last_probe: Instant
+}
+
+impl DatagramChannel {
+    fn open(server: SocketAddr, token: NetToken) -> Result<DatagramChannel, ProtocolError> {
+        let socket = match server {
+            SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+            SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+        };
+        socket.connect(server)?;
+        socket.set_nonblocking(true)?;
+
+        let mut channel = DatagramChannel {
+            socket,
+            token,
+            sequencer: Sequencer::new(),
+            confirmed: false,
+            probes: 0,
+            last_probe: Instant::now()
+        };
+        // the server answers it, until then messages go over tcp
+        channel.probe();
+
+        Ok(channel)
+    }
+
+    fn probe(&mut self) {
+        self.send(None);
+        self.probes += 1;
+        self.last_probe = Instant::now();
+    }
+
+    // probes again if the last one was not answered in time, false once it's time to give up
+    fn poll_probe(&mut self, now: Instant) -> bool {
+        if self.confirmed || now.duration_since(self.last_probe) < UDP_PROBE_INTERVAL {
+            return true;
+        }
+
+        if self.probes >= UDP_PROBES {
+            return false;
+        }
+
+        self.probe();
+
+        true
+    }
+
+    // a datagram that could not be sent is just skipped, the next one carries a newer state
+    fn send(&mut self, message: Option<&Message>) {
+        let datagram = protocol::encode_datagram(self.token, self.sequencer.next_seq(), message);
+        let _ = self.socket.send(&datagram);
+    }
+
+    fn poll_messages(&mut self) -> Vec<Result<Message, ProtocolError>> {
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        let mut results = vec![];
+
+        loop {
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                // WouldBlock, or an icmp error about an earlier datagram, tcp will tell if the server is gone
+                Err(_) => break
+            };
+
+            match protocol::decode_datagram(&buf[..len]) {
+                Ok((token, seq, message)) => if token == self.token && self.sequencer.accept(seq) {
+                    self.confirmed = true;
+                    results.extend(message.map(Ok));
+                },
+                // whatever is wrong with a datagram, it never affects the next one
+                Err(err) => if !err.is_fatal() {
+                    results.push(Err(err));
+                }
+            }
+        }
+
+        results
+    }
+}
+
+// both directions of a connection going through a simulated bad network
+struct Simulation {
+    incoming: Lane<Result<Message, ProtocolError>>,
+    outgoing: Lane<Message>,
+    // a broken stream reports its error on every read, only the first one goes into the lane
+    broken: bool
+}
 
 pub struct Connection {
-    pub socket: TcpStream,
-    pub token: NetToken
+    pub token: NetToken,
+    pub addr: SocketAddr,
+    // negotiated during the handshake, see `handshake::FEATURES`
+    pub features: u32,
+    stream: MessageStream,
+    // None if the server does not support udp, the socket could not be opened or datagrams don't get through
+    datagrams: Option<DatagramChannel>,
+    heartbeat: Heartbeat,
+    // sent by the server right after the handshake
+    secret: Option<u64>,
+    // received while waiting for something else, returned by the next poll
+    backlog: Vec<Result<Message, ProtocolError>>,
+    simulation: Option<Simulation>
+}
+
+// What it takes to get the token (and the player) of a lost connection back
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    pub token: NetToken,
+    pub secret: u64
 }
 
-pub enum EventType {
-    Spawn,
-    UpdatePos
+#[derive(Clone)]
+pub struct NetConfig {
+    // how many times per second local player state may be sent to the server
+    pub send_rate: f64,
+    // how far in the past (seconds) remote players are shown to have a state to move towards
+    pub interpolation_delay: f64,
+    pub connect_timeout: Duration,
+    pub connect_attempts: u32,
+    // pause before the second attempt, doubled for every next one
+    pub retry_backoff: Duration,
+    pub ping_interval: Duration,
+    // the server is considered gone if nothing comes from it for this long
+    pub timeout: Duration,
+    // debug setting: messages are delayed, lost, duplicated and reordered as if the network was bad
+    pub simulation: Option<NetConditions>
+}
+
+impl Default for NetConfig {
+    fn default() -> NetConfig {
+        NetConfig {
+            send_rate: 20.,
+            interpolation_delay: 0.1,
+            connect_timeout: Duration::from_secs(3),
+            connect_attempts: 3,
+            retry_backoff: Duration::from_millis(500),
+            ping_interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+            simulation: None
+        }
+    }
 }
 
 impl Connection {
-    pub fn new(mut socket: TcpStream) -> Result<Connection, String> {
-        let mut buf = [0u8; 8];
+    pub fn new(socket: TcpStream) -> Result<Connection, ProtocolError> {
+        Connection::with_config(socket, &NetConfig::default())
+    }
 
-        match socket.read(&mut buf) {
-            Ok(_) => Ok(Connection {
-                socket: socket,
-                token: BigEndian::read_u64(&buf) as usize
-            }),
-            Err(e) => Err(format!("{:?}", e.kind()))
+    pub fn with_config(mut socket: TcpStream, config: &NetConfig) -> Result<Connection, ProtocolError> {
+        // something that is not a side-run server may never answer
+        socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let welcome = handshake::client_handshake(&mut socket, &Hello::new())?;
+        socket.set_read_timeout(None)?;
+
+        let addr = socket.peer_addr()?;
+        let mut features = welcome.features;
+        let mut datagrams = None;
+
+        if features & FEATURE_UDP != 0 {
+            match DatagramChannel::open(addr, welcome.token) {
+                Ok(channel) => datagrams = Some(channel),
+                // everything still works over tcp, just with more latency on packet loss
+                Err(err) => {
+                    println!("Failed to open udp channel, falling back to tcp: {}", err);
+                    features &= !FEATURE_UDP;
+                }
+            }
         }
+
+        Ok(Connection {
+            token: welcome.token,
+            addr,
+            features,
+            stream: MessageStream::new(socket)?,
+            datagrams,
+            heartbeat: Heartbeat::new(config.ping_interval, config.timeout, Instant::now()),
+            secret: None,
+            backlog: Vec::new(),
+            simulation: config.simulation.as_ref().map(|conditions| Simulation {
+                incoming: Lane::random(conditions.clone()),
+                outgoing: Lane::random(conditions.clone()),
+                broken: false
+            })
+        })
     }
 
-    pub fn listen_events(&mut self) -> Option<(EventType, String)> {
-        let mut buf = [0u8; 64];
-        self.socket.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.features & feature != 0
+    }
+
+    // unreliable messages go over tcp until datagrams got through both ways
+    pub fn uses_udp(&self) -> bool {
+        self.datagrams.as_ref().map(|datagrams| datagrams.confirmed).unwrap_or(false)
+    }
+
+    // besides the stream polling, pings the server and tells if it stopped responding
+    pub fn poll_messages(&mut self) -> Vec<Result<Message, ProtocolError>> {
+        let now = Instant::now();
+        let send_error = self.send_simulated(now).err();
+        let broken = self.simulation.as_ref().map(|simulation| simulation.broken).unwrap_or(false);
+        let mut received = if broken { vec![] } else { self.stream.poll_messages() };
+
+        if let Some(ref mut datagrams) = self.datagrams {
+            received.extend(datagrams.poll_messages());
+        }
+
+        let filtered = self.datagrams.as_mut().map(|datagrams| !datagrams.poll_probe(now)).unwrap_or(false);
+
+        // a firewall or nat may drop udp, everything keeps going over tcp then
+        if filtered {
+            println!("No answer to udp probes, staying on tcp");
+            self.datagrams = None;
+            self.features &= !FEATURE_UDP;
+        }
+
+        let tcp_only = !self.uses_udp();
 
-        match self.socket.read(&mut buf) {
-            Ok(_) => {
-                let (event, raw_data) = buf.split_at(5);
-                let data = String::from_utf8_lossy(&raw_data).into_owned();
+        if let Some(ref mut simulation) = self.simulation {
+            for result in received.drain(..) {
+                let reliable = tcp_only || match result {
+                    Ok(ref message) => message.channel() == Channel::Reliable,
+                    Err(_) => true
+                };
 
-                match Connection::parse_event_type(&event) {
-                    Some(e) => Some((e, data)),
-                    None => None
+                if matches!(result, Err(ref err) if err.is_fatal()) {
+                    simulation.broken = true;
                 }
-            },
-            Err(_) => None
+
+                simulation.incoming.push(result, reliable, now);
+            }
+
+            received = simulation.incoming.pop_due(now);
+        }
+
+        // keep a fatal error the last item
+        let fatal_idx = received.iter().position(|result| matches!(*result, Err(ref err) if err.is_fatal()));
+        let mut fatal = fatal_idx.map(|idx| received.remove(idx)).or_else(|| send_error.map(Err));
+
+        // a bad packet or the error of a broken stream is no sign the server is alive
+        if received.iter().any(|result| result.is_ok()) {
+            self.heartbeat.on_received(now);
         }
+
+        let mut results = mem::take(&mut self.backlog);
+
+        // pongs and the session answer the connection itself, nobody else needs them
+        for result in received {
+            match result {
+                Ok(Message::Pong { id }) => self.heartbeat.on_pong(id, now),
+                Ok(Message::Session { secret }) => self.secret = Some(secret),
+                other => results.push(other)
+            }
+        }
+
+        if fatal.is_none() {
+            if self.heartbeat.is_timed_out(now) {
+                fatal = Some(Err(ProtocolError::TimedOut));
+            } else if let Some(id) = self.heartbeat.poll(now) {
+                fatal = self.send(&Message::Ping { id }).err().map(Err);
+            }
+        }
+
+        results.extend(fatal);
+
+        results
     }
 
-    pub fn send_spawn_event(&mut self, name: String, pos: Vec2d, color: Color) -> Result<(), String> {
-        let token = self.token.clone();
-        let x = pos[0];
-        let y = pos[1];
+    // None until the server tells the secret
+    pub fn session(&self) -> Option<Session> {
+        self.secret.map(|secret| Session { token: self.token, secret })
+    }
 
-        let u32_color = {
-            let to_255 = 255f32;
-            let r = color[0] * to_255;
-            let g = color[1] * to_255;
-            let b = color[2] * to_255;
-            let a = color[3] * to_255;
+    // asks the server to give back the token of a lost connection, waiting for the answer;
+    // returns false if the session expired and the connection keeps its new token
+    pub fn resume(&mut self, session: &Session) -> Result<bool, ProtocolError> {
+        self.send(&Message::Resume { token: session.token, secret: session.secret })?;
 
-            let rp = (r as u32) << 24;
-            let gp = (g as u32) << 16;
-            let bp = (b as u32) << 8;
-            let ap = a as u32;
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
 
-            (rp | gp | bp | ap)
-        };
+        while Instant::now() < deadline {
+            let mut token = None;
 
+            for result in self.poll_messages() {
+                match result {
+                    Ok(Message::Resumed { token: resumed }) => token = Some(resumed),
+                    Err(ref err) if err.is_fatal() => return Err(err.clone()),
+                    other => self.backlog.push(other)
+                }
+            }
 
-        self.socket.write_all(format!("SPWN {}|{}|{}x{}|{}\r\n", token, name, x, y, u32_color).as_bytes()).unwrap();
-        self.socket.flush().unwrap();
+            if let Some(token) = token {
+                self.token = token;
 
-        Ok(())
+                if let Some(ref mut datagrams) = self.datagrams {
+                    datagrams.token = token;
+                }
+
+                return Ok(token == session.token);
+            }
+
+            thread::sleep(RESUME_POLL_INTERVAL);
+        }
+
+        Err(ProtocolError::TimedOut)
     }
 
-    pub fn send_update_pos_event(&mut self, pos: Vec2d) -> Result<(), String> {
-        let x = pos[0];
-        let y = pos[1];
-        let token = self.token.clone();
-        self.socket.write_all(format!("UPDP {}|{}x{}\r\n", token, x, y).as_bytes()).unwrap();
-        self.socket.flush().unwrap();
+    pub fn rtt(&self) -> Option<Duration> {
+        self.heartbeat.rtt()
+    }
 
-        Ok(())
+    // share of recent datagrams from the server that were lost, None while nothing goes over udp
+    pub fn packet_loss(&self) -> Option<f64> {
+        self.datagrams.as_ref()
+            .filter(|datagrams| datagrams.confirmed)
+            .map(|datagrams| datagrams.sequencer.packet_loss())
     }
 
-    pub fn parse_event_type(buf: &[u8]) -> Option<EventType> {
-        let cow_str = String::from_utf8_lossy(buf).into_owned();
-        let (event_name, _) = cow_str.as_str().split_at(4);
-
-        match event_name {
-            "SPWN" => Some(EventType::Spawn),
-            "UPDP" => Some(EventType::UpdatePos),
-            _ => None
-        }
-    }
-
-    pub fn parse_update_pos_event(data: String) -> Result<(usize, Vec2d), String> {
-        let str: Vec<&str> = data.as_str().split("\r\n").collect();
-        let data_str = str[0].trim();
-        let data_parts: Vec<&str> = data_str.split("|").collect();
-        let token = data_parts[0].parse::<u64>().expect("token") as usize;
-        let coords: Vec<&str> = data_parts[1].split("x").collect();
-        let pos = Vec2d::from([
-            coords[0].parse::<f64>().expect("x"),
-            coords[1].parse::<f64>().expect("y")
-        ]);
-
-        Ok((token, pos))
-    }
-
-    pub fn parse_spawn_event(data: String) -> Result<(usize, String, Vec2d, Color), String> {
-        let str: Vec<&str> = data.as_str().split("\r\n").collect();
-        let data_str = str[0].trim();
-        let data_parts: Vec<&str> = data_str.split("|").collect();
-        let token = data_parts[0].parse::<u64>().expect("token") as usize;
-        let name = data_parts[1].to_string();
-        let coords: Vec<&str> = data_parts[2].split("x").collect();
-        let pos = Vec2d::from([
-            coords[0].parse::<f64>().expect("x"),
-            coords[1].parse::<f64>().expect("y")
-        ]);
-        let color_u = data_parts[3].parse::<u32>().expect("color");
-
-        let inv_255 = 1.0f32 / 255.0f32;
-        let color = {
-            let rp = (color_u >> 24) as u8;
-            let gp = (color_u >> 16) as u8;
-            let bp = (color_u >> 8) as u8;
-            let ap = color_u as u8;
-
-            [rp as f32 * inv_255, gp as f32 * inv_255, bp as f32 * inv_255, ap as f32 * inv_255]
+    // how long nothing was received from the server
+    pub fn silence(&self) -> Duration {
+        self.heartbeat.silence(Instant::now())
+    }
+
+    pub fn send(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        let tcp_only = !self.uses_udp();
+
+        if let Some(ref mut simulation) = self.simulation {
+            let reliable = tcp_only || message.channel() == Channel::Reliable;
+            simulation.outgoing.push(message.clone(), reliable, Instant::now());
+
+            return Ok(());
+        }
+
+        self.send_now(message)
+    }
+
+    // each message goes over the channel its kind asks for, if the connection has it
+    fn send_now(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        match (message.channel(), self.datagrams.as_mut()) {
+            (Channel::Unreliable, Some(datagrams)) if datagrams.confirmed => {
+                datagrams.send(Some(message));
+
+                Ok(())
+            },
+            _ => self.stream.send(message)
+        }
+    }
+
+    // sends simulated messages that made it through the bad network by now
+    fn send_simulated(&mut self, now: Instant) -> Result<(), ProtocolError> {
+        let due = match self.simulation {
+            Some(ref mut simulation) => simulation.outgoing.pop_due(now),
+            None => return Ok(())
         };
 
-        Ok((token, name, pos, color))
+        for message in due {
+            self.send_now(&message)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn send_spawn_event(&mut self, name: String, pos: Vec2d, color: Color) -> Result<(), ProtocolError> {
+        let token = self.token;
+
+        self.send(&Message::Spawn { token, name, pos, color })
+    }
+
+    pub fn send_update_pos_event(&mut self, seq: u32, pos: Vec2d, rotation: f64, velocity: Vec2d) -> Result<(), ProtocolError> {
+        let token = self.token;
+
+        self.send(&Message::UpdatePos { token, seq, pos, rotation, velocity })
+    }
+
+    pub fn send_chat(&mut self, text: String) -> Result<(), ProtocolError> {
+        let token = self.token;
+
+        self.send(&Message::Chat { token, text })
+    }
+
+    fn open(addr: &SocketAddr, config: &NetConfig) -> Result<Connection, ProtocolError> {
+        let socket = TcpStream::connect_timeout(addr, config.connect_timeout)?;
+
+        Connection::with_config(socket, config)
+    }
+}
+
+pub enum ConnectEvent {
+    Attempt(u32),
+    Connected(Box<Connection>),
+    Failed(ProtocolError)
+}
+
+// Connects on a background thread, so the game loop never waits for the network.
+// Dropping the connector abandons the attempt.
+pub struct Connector {
+    events: Receiver<ConnectEvent>
+}
+
+impl Connector {
+    pub fn start(host: String, config: &NetConfig) -> Connector {
+        Connector::spawn(host, config, None)
+    }
+
+    // connects again after the connection was lost and asks for the old session,
+    // the connected connection has the old token if the server still remembered it
+    pub fn resume(host: String, config: &NetConfig, session: Session) -> Connector {
+        Connector::spawn(host, config, Some(session))
+    }
+
+    fn spawn(host: String, config: &NetConfig, session: Option<Session>) -> Connector {
+        let (sender, events) = mpsc::channel();
+        let config = config.clone();
+
+        thread::spawn(move || Connector::run(&host, &config, session.as_ref(), &sender));
+
+        Connector { events }
+    }
+
+    // returns None while there is nothing new about the attempt
+    pub fn poll(&mut self) -> Option<ConnectEvent> {
+        match self.events.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(ConnectEvent::Failed(ProtocolError::Disconnected))
+        }
+    }
+
+    fn run(host: &str, config: &NetConfig, session: Option<&Session>, events: &Sender<ConnectEvent>) {
+        let mut backoff = config.retry_backoff;
+        let mut last_error = ProtocolError::Io(ErrorKind::NotFound);
+
+        for attempt in 1..config.connect_attempts + 1 {
+            if attempt > 1 {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+
+            // nobody waits for the result anymore
+            if events.send(ConnectEvent::Attempt(attempt)).is_err() {
+                return;
+            }
+
+            let result = host.to_socket_addrs()
+                .map_err(ProtocolError::from)
+                .and_then(|mut addrs| addrs.next().ok_or(ProtocolError::Io(ErrorKind::NotFound)))
+                .and_then(|addr| Connection::open(&addr, config))
+                .and_then(|mut connection| match session {
+                    Some(session) => connection.resume(session).map(|_| connection),
+                    None => Ok(connection)
+                });
+
+            match result {
+                Ok(connection) => {
+                    let _ = events.send(ConnectEvent::Connected(Box::new(connection)));
+                    return;
+                },
+                // the server answered, asking again will not change its mind
+                Err(err @ ProtocolError::BadMagic(_)) |
+                Err(err @ ProtocolError::VersionMismatch { .. }) |
+                Err(err @ ProtocolError::Rejected) => {
+                    let _ = events.send(ConnectEvent::Failed(err));
+                    return;
+                },
+                Err(err) => last_error = err
+            }
+        }
+
+        let _ = events.send(ConnectEvent::Failed(last_error));
     }
 }
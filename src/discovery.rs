@@ -0,0 +1,219 @@
+// LAN discovery: clients broadcast a probe to the default server port and every server that gets it
+// answers from its game socket, so where the reply comes from is where to connect.
+//
+//   client -> server: | magic: u32 |
+//   server -> client: | magic: u32 | version: u8 | players: u16 | name_len: u8 | name: utf8 |
+//
+// Like the handshake its layout never changes, so servers of other versions are listed too.
+// A probe can't be mistaken for a game datagram, those start with a token that is far below 2^32.
+
+use std::io::{Cursor, ErrorKind, Read};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use protocol::{ProtocolError, PROTOCOL_VERSION};
+
+pub const PROBE_MAGIC: u32 = 0x5352_3f3f; // "SR??"
+pub const REPLY_MAGIC: u32 = 0x5352_2121; // "SR!!"
+// servers listen on it unless told otherwise
+pub const DISCOVERY_PORT: u16 = 7001;
+// longer server names are cut, in bytes
+pub const MAX_NAME_LEN: usize = 64;
+const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+// a server that missed this many probes is gone
+const MISSED_PROBES: u32 = 3;
+const MAX_REPLY_LEN: usize = 8 + MAX_NAME_LEN;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: u8,
+    pub players: u16
+}
+
+impl ServerInfo {
+    pub fn is_compatible(&self) -> bool {
+        self.version == PROTOCOL_VERSION
+    }
+}
+
+pub fn encode_probe() -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4);
+    buf.write_u32::<BigEndian>(PROBE_MAGIC).unwrap();
+
+    buf
+}
+
+pub fn is_probe(buf: &[u8]) -> bool {
+    buf.len() == 4 && Cursor::new(buf).read_u32::<BigEndian>().ok() == Some(PROBE_MAGIC)
+}
+
+pub fn encode_reply(info: &ServerInfo) -> Vec<u8> {
+    let mut len = info.name.len().min(MAX_NAME_LEN);
+
+    while !info.name.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    let mut buf = Vec::with_capacity(8 + len);
+    buf.write_u32::<BigEndian>(REPLY_MAGIC).unwrap();
+    buf.write_u8(info.version).unwrap();
+    buf.write_u16::<BigEndian>(info.players).unwrap();
+    buf.write_u8(len as u8).unwrap();
+    buf.extend_from_slice(&info.name.as_bytes()[..len]);
+
+    buf
+}
+
+pub fn decode_reply(buf: &[u8]) -> Result<ServerInfo, ProtocolError> {
+    let mut reader = Cursor::new(buf);
+    let magic = reader.read_u32::<BigEndian>().map_err(|_| ProtocolError::Truncated)?;
+
+    if magic != REPLY_MAGIC {
+        return Err(ProtocolError::BadMagic(magic));
+    }
+
+    let version = reader.read_u8().map_err(|_| ProtocolError::Truncated)?;
+    let players = reader.read_u16::<BigEndian>().map_err(|_| ProtocolError::Truncated)?;
+    let len = reader.read_u8().map_err(|_| ProtocolError::Truncated)? as usize;
+
+    let mut name = vec![0u8; len];
+    reader.read_exact(&mut name).map_err(|_| ProtocolError::Truncated)?;
+    let name = String::from_utf8(name).map_err(|_| ProtocolError::MalformedField("name"))?;
+
+    Ok(ServerInfo { name, version, players })
+}
+
+struct Discovered {
+    addr: SocketAddr,
+    info: ServerInfo,
+    last_seen: Instant
+}
+
+// Probes for servers every few seconds and keeps the list of those that answered.
+pub struct Browser {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    next_probe: Instant,
+    servers: Vec<Discovered>
+}
+
+impl Browser {
+    // looks for servers on the default port all over the local network
+    pub fn new() -> Result<Browser, ProtocolError> {
+        let broadcast = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), DISCOVERY_PORT));
+
+        Browser::with_targets(vec![broadcast])
+    }
+
+    pub fn with_targets(targets: Vec<SocketAddr>) -> Result<Browser, ProtocolError> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Browser { socket, targets, next_probe: Instant::now(), servers: vec![] })
+    }
+
+    // sends a probe when it is time for one and collects the replies,
+    // returns whether the list of servers changed
+    pub fn poll(&mut self) -> bool {
+        let now = Instant::now();
+
+        if now >= self.next_probe {
+            for target in self.targets.iter() {
+                // no network, nothing to discover; the next probe may have more luck
+                if let Err(e) = self.socket.send_to(&encode_probe(), target) {
+                    println!("Failed to probe {} for servers: {}", target, e);
+                }
+            }
+
+            self.next_probe = now + PROBE_INTERVAL;
+        }
+
+        let mut changed = self.receive_replies(now);
+
+        let before = self.servers.len();
+        self.servers.retain(|server| now.duration_since(server.last_seen) < PROBE_INTERVAL * MISSED_PROBES);
+        changed |= self.servers.len() != before;
+
+        changed
+    }
+
+    // servers that answered recently, in the order they were found
+    pub fn servers(&self) -> Vec<(SocketAddr, ServerInfo)> {
+        self.servers.iter()
+            .map(|server| (server.addr, server.info.clone()))
+            .collect()
+    }
+
+    fn receive_replies(&mut self, now: Instant) -> bool {
+        let mut buf = [0u8; MAX_REPLY_LEN];
+        let mut changed = false;
+
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("Failed to receive server reply: {}", e);
+                    break;
+                }
+            };
+
+            let info = match decode_reply(&buf[..len]) {
+                Ok(info) => info,
+                Err(err) => {
+                    println!("Dropped bad server reply from {}: {}", addr, err);
+                    continue;
+                }
+            };
+
+            match self.servers.iter_mut().find(|server| server.addr == addr) {
+                Some(server) => {
+                    changed |= server.info != info;
+                    server.info = info;
+                    server.last_seen = now;
+                },
+                None => {
+                    self.servers.push(Discovered { addr, info, last_seen: now });
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_round_trip() {
+        let info = ServerInfo { name: "basement".to_string(), version: 3, players: 2 };
+
+        assert_eq!(decode_reply(&encode_reply(&info)), Ok(info));
+    }
+
+    #[test]
+    fn long_name_is_cut_on_char_boundary() {
+        let info = ServerInfo { name: "ж".repeat(MAX_NAME_LEN), version: 3, players: 0 };
+        let decoded = decode_reply(&encode_reply(&info)).unwrap();
+
+        assert_eq!(decoded.name, "ж".repeat(MAX_NAME_LEN / 2));
+    }
+
+    #[test]
+    fn probe_is_not_a_game_datagram() {
+        assert!(is_probe(&encode_probe()));
+        assert!(!is_probe(&encode_reply(&ServerInfo { name: String::new(), version: 1, players: 0 })));
+        assert!(!is_probe(&[0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn foreign_reply_is_rejected() {
+        assert_eq!(decode_reply(&encode_probe()), Err(ProtocolError::BadMagic(PROBE_MAGIC)));
+    }
+}
@@ -27,13 +27,12 @@ impl GameCycle {
                     match button_state {
                         ButtonState::Press => self.scene.key_press(button),
                         ButtonState::Release => self.scene.key_release(button),
-                        _ => ()
                     }
                 });
 
                 event.render(|_| {
-                    window.draw_2d(&event, |mut ctx, mut graph| {
-                        self.scene.draw(&mut ctx, &mut graph, &mut self.asset_manager).unwrap();
+                    window.draw_2d(&event, |mut ctx, graph| {
+                        self.scene.draw(&mut ctx, graph, &mut self.asset_manager).unwrap();
                     });
                 });
 
@@ -41,9 +40,9 @@ impl GameCycle {
                     self.scene.update(dt).unwrap();
                 });
 
-                self.scene.switcher()
-                    .get_next()
-                    .and_then(|next_scene| Some(self.set_scene(next_scene)));
+                if let Some(next_scene) = self.scene.switcher().get_next() {
+                    self.set_scene(next_scene);
+                }
             } else {
                 break;
             }
@@ -0,0 +1,216 @@
+// Handshake happens right after the tcp connection is established, before any frame is sent:
+//
+//   client -> server: | magic: u32 | version: u8 | features: u32 |
+//   server -> client: | magic: u32 | version: u8 | features: u32 | status: u8 | token: u64 |
+//
+// Its layout never changes between protocol versions, so mismatching peers can always
+// tell what is wrong. A server that rejects the client closes the connection after replying.
+
+use std::io::{Cursor, Read, Write};
+use std::time::Duration;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use protocol::{NetToken, ProtocolError, PROTOCOL_VERSION};
+
+pub const MAGIC: u32 = 0x5352_554e; // "SRUN"
+pub const HELLO_LEN: usize = 9;
+pub const WELCOME_LEN: usize = 18;
+// how long a peer may take to answer the handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Optional capabilities as bit flags. Peers only use the ones both of them support.
+// unreliable messages may go over udp, on the same port as the tcp connection
+pub const FEATURE_UDP: u32 = 1;
+pub const FEATURES: u32 = FEATURE_UDP;
+
+const STATUS_ACCEPTED: u8 = 0;
+const STATUS_REJECTED: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub version: u8,
+    pub features: u32
+}
+
+impl Hello {
+    pub fn new() -> Hello {
+        Hello { version: PROTOCOL_VERSION, features: FEATURES }
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Hello {
+        Hello::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Welcome {
+    pub version: u8,
+    pub features: u32,
+    pub accepted: bool,
+    // meaningless if the client was not accepted
+    pub token: NetToken
+}
+
+impl Welcome {
+    pub fn accept(token: NetToken, client_features: u32) -> Welcome {
+        Welcome { version: PROTOCOL_VERSION, features: FEATURES & client_features, accepted: true, token }
+    }
+
+    pub fn reject() -> Welcome {
+        Welcome { version: PROTOCOL_VERSION, features: 0, accepted: false, token: 0 }
+    }
+}
+
+pub fn encode_hello(hello: &Hello) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HELLO_LEN);
+    buf.write_u32::<BigEndian>(MAGIC).unwrap();
+    buf.write_u8(hello.version).unwrap();
+    buf.write_u32::<BigEndian>(hello.features).unwrap();
+
+    buf
+}
+
+pub fn decode_hello(buf: &[u8]) -> Result<Hello, ProtocolError> {
+    let mut reader = Cursor::new(buf);
+    read_magic(&mut reader)?;
+
+    Ok(Hello {
+        version: reader.read_u8().map_err(|_| ProtocolError::Truncated)?,
+        features: reader.read_u32::<BigEndian>().map_err(|_| ProtocolError::Truncated)?
+    })
+}
+
+pub fn encode_welcome(welcome: &Welcome) -> Vec<u8> {
+    let status = if welcome.accepted { STATUS_ACCEPTED } else { STATUS_REJECTED };
+
+    let mut buf = Vec::with_capacity(WELCOME_LEN);
+    buf.write_u32::<BigEndian>(MAGIC).unwrap();
+    buf.write_u8(welcome.version).unwrap();
+    buf.write_u32::<BigEndian>(welcome.features).unwrap();
+    buf.write_u8(status).unwrap();
+    buf.write_u64::<BigEndian>(welcome.token as u64).unwrap();
+
+    buf
+}
+
+pub fn decode_welcome(buf: &[u8]) -> Result<Welcome, ProtocolError> {
+    let mut reader = Cursor::new(buf);
+    read_magic(&mut reader)?;
+
+    let version = reader.read_u8().map_err(|_| ProtocolError::Truncated)?;
+    let features = reader.read_u32::<BigEndian>().map_err(|_| ProtocolError::Truncated)?;
+    let accepted = match reader.read_u8().map_err(|_| ProtocolError::Truncated)? {
+        STATUS_ACCEPTED => true,
+        STATUS_REJECTED => false,
+        _ => return Err(ProtocolError::MalformedField("status"))
+    };
+    let token = reader.read_u64::<BigEndian>().map_err(|_| ProtocolError::Truncated)? as NetToken;
+
+    Ok(Welcome { version, features, accepted, token })
+}
+
+pub fn check_version(remote: u8) -> Result<(), ProtocolError> {
+    if remote == PROTOCOL_VERSION {
+        Ok(())
+    } else {
+        Err(ProtocolError::VersionMismatch { local: PROTOCOL_VERSION, remote })
+    }
+}
+
+// client side of the handshake over a blocking stream,
+// returns the token and the negotiated features
+pub fn client_handshake<S: Read + Write>(stream: &mut S, hello: &Hello) -> Result<Welcome, ProtocolError> {
+    stream.write_all(&encode_hello(hello))?;
+    stream.flush()?;
+
+    let mut buf = [0u8; WELCOME_LEN];
+    stream.read_exact(&mut buf)?;
+
+    let welcome = decode_welcome(&buf)?;
+    check_version(welcome.version)?;
+
+    if welcome.accepted {
+        Ok(welcome)
+    } else {
+        Err(ProtocolError::Rejected)
+    }
+}
+
+fn read_magic(reader: &mut Cursor<&[u8]>) -> Result<(), ProtocolError> {
+    let magic = reader.read_u32::<BigEndian>().map_err(|_| ProtocolError::Truncated)?;
+
+    if magic == MAGIC {
+        Ok(())
+    } else {
+        Err(ProtocolError::BadMagic(magic))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello { version: 3, features: 0b101 };
+        let buf = encode_hello(&hello);
+
+        assert_eq!(buf.len(), HELLO_LEN);
+        assert_eq!(decode_hello(&buf), Ok(hello));
+    }
+
+    #[test]
+    fn welcome_round_trip() {
+        let welcome = Welcome { version: 3, features: 0b1, accepted: true, token: 12 };
+        let buf = encode_welcome(&welcome);
+
+        assert_eq!(buf.len(), WELCOME_LEN);
+        assert_eq!(decode_welcome(&buf), Ok(welcome));
+        assert_eq!(decode_welcome(&encode_welcome(&Welcome::reject())), Ok(Welcome::reject()));
+    }
+
+    #[test]
+    fn foreign_peer_is_detected() {
+        assert_eq!(decode_hello(b"GET / HTTP/1.1"), Err(ProtocolError::BadMagic(0x4745_5420)));
+    }
+
+    #[test]
+    fn features_are_intersected() {
+        assert_eq!(Welcome::accept(1, !0).features, FEATURES);
+        assert_eq!(Welcome::accept(1, 0).features, 0);
+    }
+
+    #[test]
+    fn client_handshake_reports_version_mismatch() {
+        let reply = encode_welcome(&Welcome { version: PROTOCOL_VERSION + 1, features: 0, accepted: false, token: 0 });
+        let mut stream = MockStream { input: Cursor::new(reply), output: vec![] };
+
+        assert_eq!(
+            client_handshake(&mut stream, &Hello::new()),
+            Err(ProtocolError::VersionMismatch { local: PROTOCOL_VERSION, remote: PROTOCOL_VERSION + 1 })
+        );
+        assert_eq!(decode_hello(&stream.output), Ok(Hello::new()));
+    }
+
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
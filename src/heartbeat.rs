@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// a ping without a pong for this long is given up on
+const LOST_AFTER: Duration = Duration::from_secs(2);
+
+// Keeps a connection alive with pings and measures the round-trip time by the pongs.
+pub struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    next_id: u32,
+    next_ping: Instant,
+    last_received: Instant,
+    // sent pings waiting for a pong
+    in_flight: VecDeque<(u32, Instant)>,
+    // smoothed round-trip time
+    rtt: Option<Duration>
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration, now: Instant) -> Heartbeat {
+        Heartbeat {
+            interval,
+            timeout,
+            next_id: 0,
+            next_ping: now,
+            last_received: now,
+            in_flight: VecDeque::new(),
+            rtt: None
+        }
+    }
+
+    // returns the id of the ping to send, if it is time for one
+    pub fn poll(&mut self, now: Instant) -> Option<u32> {
+        while let Some(&(_, sent)) = self.in_flight.front() {
+            if now.duration_since(sent) < LOST_AFTER {
+                break;
+            }
+
+            self.in_flight.pop_front();
+        }
+
+        if now < self.next_ping {
+            return None;
+        }
+
+        let id = self.next_id;
+        self.next_id = id.wrapping_add(1);
+        self.next_ping = now + self.interval;
+        self.in_flight.push_back((id, now));
+
+        Some(id)
+    }
+
+    // anything from the peer proves it is alive
+    pub fn on_received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    pub fn on_pong(&mut self, id: u32, now: Instant) {
+        let idx = match self.in_flight.iter().position(|&(sent_id, _)| sent_id == id) {
+            Some(idx) => idx,
+            // duplicated, or late and already given up on
+            None => return
+        };
+
+        let (_, sent) = self.in_flight.remove(idx).unwrap();
+        let sample = now.duration_since(sent);
+
+        self.rtt = Some(match self.rtt {
+            // smoothed like tcp does, so a single slow pong does not make the ping jump
+            Some(rtt) => rtt * 7 / 8 + sample / 8,
+            None => sample
+        });
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    // how long nothing was received from the peer
+    pub fn silence(&self, now: Instant) -> Duration {
+        now.duration_since(self.last_received)
+    }
+
+    pub fn is_timed_out(&self, now: Instant) -> bool {
+        self.silence(now) >= self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat(now: Instant) -> Heartbeat {
+        Heartbeat::new(Duration::from_secs(1), Duration::from_secs(5), now)
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn pings_are_sent_every_interval() {
+        let start = Instant::now();
+        let mut heartbeat = heartbeat(start);
+
+        assert_eq!(heartbeat.poll(start), Some(0));
+        assert_eq!(heartbeat.poll(start + ms(500)), None);
+        assert_eq!(heartbeat.poll(start + ms(1000)), Some(1));
+    }
+
+    #[test]
+    fn rtt_is_measured_and_smoothed() {
+        let start = Instant::now();
+        let mut heartbeat = heartbeat(start);
+        assert_eq!(heartbeat.rtt(), None);
+
+        let id = heartbeat.poll(start).unwrap();
+        heartbeat.on_pong(id, start + ms(80));
+        assert_eq!(heartbeat.rtt(), Some(ms(80)));
+
+        let id = heartbeat.poll(start + ms(1000)).unwrap();
+        heartbeat.on_pong(id, start + ms(1160));
+        assert_eq!(heartbeat.rtt(), Some(ms(90)));
+    }
+
+    #[test]
+    fn late_pongs_are_ignored() {
+        let start = Instant::now();
+        let mut heartbeat = heartbeat(start);
+
+        let id = heartbeat.poll(start).unwrap();
+        heartbeat.poll(start + ms(3000));
+
+        heartbeat.on_pong(id, start + ms(3100));
+        assert_eq!(heartbeat.rtt(), None);
+    }
+
+    #[test]
+    fn silent_peer_times_out() {
+        let start = Instant::now();
+        let mut heartbeat = heartbeat(start);
+
+        heartbeat.on_received(start + ms(3000));
+
+        assert!(!heartbeat.is_timed_out(start + ms(7000)));
+        assert!(heartbeat.is_timed_out(start + ms(8000)));
+    }
+}
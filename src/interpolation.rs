@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use protocol::Vec2d;
+
+// more is never needed with any sane interpolation delay
+const MAX_SNAPSHOTS: usize = 32;
+// how far (seconds) past the newest state an object keeps moving with its velocity,
+// after that it stops so a lost player does not walk away
+const MAX_EXTRAPOLATION: f64 = 0.25;
+
+// State of a remote object as received at `time` (seconds of the local clock)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub time: f64,
+    pub pos: Vec2d,
+    pub rotation: f64,
+    // pixels per second
+    pub velocity: Vec2d
+}
+
+impl Snapshot {
+    // where the object is `time` if it kept going, for a limited time
+    fn extrapolate(&self, time: f64) -> Vec2d {
+        let ahead = (time - self.time).clamp(0., MAX_EXTRAPOLATION);
+
+        [self.pos[0] + self.velocity[0] * ahead, self.pos[1] + self.velocity[1] * ahead]
+    }
+}
+
+// Remembers recently received states of a remote object, so it can be shown
+// slightly in the past, moving smoothly between two known states.
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>
+}
+
+impl SnapshotBuffer {
+    pub fn new() -> SnapshotBuffer {
+        SnapshotBuffer { snapshots: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        // the clock is local, so anything older than the newest state is out of order
+        if let Some(last) = self.snapshots.back() {
+            if snapshot.time < last.time {
+                return;
+            }
+        }
+
+        if self.snapshots.len() == MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back(snapshot);
+    }
+
+    // position and rotation at `time`, extrapolated a bit past the newest state
+    pub fn sample(&mut self, time: f64) -> Option<(Vec2d, f64)> {
+        // the pair surrounding `time` is kept, older snapshots will never be needed again
+        while self.snapshots.len() > 2 && self.snapshots[1].time <= time {
+            self.snapshots.pop_front();
+        }
+
+        let (from, to) = match (self.snapshots.front(), self.snapshots.get(1)) {
+            (Some(from), Some(to)) => (from, to),
+            (Some(only), None) => return Some((only.extrapolate(time), only.rotation)),
+            _ => return None
+        };
+
+        // the next state is late
+        if time > to.time {
+            return Some((to.extrapolate(time), to.rotation));
+        }
+
+        let span = to.time - from.time;
+        let t = if span > 0. {
+            ((time - from.time) / span).clamp(0., 1.)
+        } else {
+            1.
+        };
+
+        let pos = [
+            from.pos[0] + (to.pos[0] - from.pos[0]) * t,
+            from.pos[1] + (to.pos[1] - from.pos[1]) * t
+        ];
+
+        Some((pos, lerp_angle(from.rotation, to.rotation, t)))
+    }
+}
+
+impl Default for SnapshotBuffer {
+    fn default() -> SnapshotBuffer {
+        SnapshotBuffer::new()
+    }
+}
+
+// turns the shortest way, so -PI..PI wrapping does not spin the object around
+pub fn lerp_angle(from: f64, to: f64, t: f64) -> f64 {
+    let mut delta = (to - from) % (2. * PI);
+
+    if delta > PI {
+        delta -= 2. * PI;
+    } else if delta < -PI {
+        delta += 2. * PI;
+    }
+
+    from + delta * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(time: f64, x: f64, rotation: f64) -> Snapshot {
+        Snapshot { time, pos: [x, 0.], rotation, velocity: [0., 0.] }
+    }
+
+    #[test]
+    fn empty_buffer_has_nothing_to_show() {
+        assert_eq!(SnapshotBuffer::new().sample(1.), None);
+    }
+
+    #[test]
+    fn single_snapshot_is_held() {
+        let mut buffer = SnapshotBuffer::new();
+        buffer.push(snapshot(1., 10., 0.5));
+
+        assert_eq!(buffer.sample(0.), Some(([10., 0.], 0.5)));
+        assert_eq!(buffer.sample(5.), Some(([10., 0.], 0.5)));
+    }
+
+    #[test]
+    fn interpolates_between_surrounding_snapshots() {
+        let mut buffer = SnapshotBuffer::new();
+        buffer.push(snapshot(0., 0., 0.));
+        buffer.push(snapshot(1., 10., 1.));
+        buffer.push(snapshot(2., 30., 1.));
+
+        assert_eq!(buffer.sample(0.5), Some(([5., 0.], 0.5)));
+        assert_eq!(buffer.sample(1.5), Some(([20., 0.], 1.)));
+    }
+
+    #[test]
+    fn holds_newest_snapshot_when_late() {
+        let mut buffer = SnapshotBuffer::new();
+        buffer.push(snapshot(0., 0., 0.));
+        buffer.push(snapshot(1., 10., 0.));
+
+        assert_eq!(buffer.sample(3.), Some(([10., 0.], 0.)));
+    }
+
+    #[test]
+    fn extrapolates_with_velocity_for_a_while() {
+        let mut buffer = SnapshotBuffer::new();
+        buffer.push(snapshot(0., 0., 0.));
+        buffer.push(Snapshot { velocity: [100., -20.], ..snapshot(1., 10., 0.) });
+
+        assert_eq!(buffer.sample(1.125), Some(([22.5, -2.5], 0.)));
+        assert_eq!(buffer.sample(5.), Some(([10. + 100. * MAX_EXTRAPOLATION, -20. * MAX_EXTRAPOLATION], 0.)));
+    }
+
+    #[test]
+    fn out_of_order_snapshot_is_ignored() {
+        let mut buffer = SnapshotBuffer::new();
+        buffer.push(snapshot(0., 0., 0.));
+        buffer.push(snapshot(2., 20., 0.));
+        buffer.push(snapshot(1., 100., 0.));
+
+        assert_eq!(buffer.sample(1.), Some(([10., 0.], 0.)));
+    }
+
+    #[test]
+    fn buffer_is_bounded() {
+        let mut buffer = SnapshotBuffer::new();
+
+        for i in 0..MAX_SNAPSHOTS * 2 {
+            buffer.push(snapshot(i as f64, i as f64, 0.));
+        }
+
+        assert_eq!(buffer.snapshots.len(), MAX_SNAPSHOTS);
+    }
+
+    #[test]
+    fn rotation_takes_shortest_way() {
+        let from = PI - 0.1;
+        let to = -PI + 0.1;
+        let middle = lerp_angle(from, to, 0.5);
+
+        assert!((middle - PI).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,13 @@
+extern crate byteorder;
+
+pub mod protocol;
+pub mod handshake;
+pub mod interpolation;
+pub mod snapshot;
+pub mod world;
+pub mod heartbeat;
+pub mod netsim;
+pub mod discovery;
+pub mod connection;
+pub mod admin;
+pub mod server;
@@ -1,24 +1,24 @@
 extern crate piston_window;
-extern crate byteorder;
 extern crate find_folder;
 extern crate vecmath;
 extern crate cgmath;
 extern crate collision;
 extern crate button_tracker;
+extern crate side_run;
 
 #[macro_use]
 extern crate conrod;
 
 mod scenes;
-mod connection;
 mod game_cycle;
 mod asset_manager;
 
-use piston_window::{PistonWindow, WindowSettings, TextureSettings, G2dTexture, Flip};
+use piston_window::{PistonWindow, WindowSettings, TextureSettings, G2dTexture, Glyphs, Flip};
 use scenes::menu::Menu;
 use game_cycle::GameCycle;
 use asset_manager::AssetManager;
 use std::path::Path;
+use side_run::{connection, discovery, netsim, protocol, interpolation, server, snapshot, world};
 
 const WIDTH: u32 = 800;
 const HEIGHT: u32 = 600;
@@ -50,8 +50,14 @@ pub fn main() {
             Flip::None,
             &TextureSettings::new()
         ).unwrap();
+        let glyphs = Glyphs::new(
+            Path::new("assets/fonts/Terminus.ttf"),
+            factory.clone(),
+            TextureSettings::new()
+        ).unwrap();
 
         let mut manager = AssetManager::new();
+        manager.set_glyphs(glyphs);
         manager.add_texture("ui_cache", ui_texture_cache);
         manager.add_texture("player_sprite", player_sprite);
         manager.add_texture("floor", floor);
@@ -0,0 +1,262 @@
+// Fakes a bad network on top of a good one, to see how the game copes with it on loopback.
+// Everything that goes through a `Lane` comes out late; unreliable items may also be lost,
+// duplicated or overtaken by later ones, while reliable items always arrive and keep their order,
+// like they do over tcp.
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+// turns the simulation on for the game client, see `NetConditions::parse` for the format
+pub const ENV_VAR: &str = "SIDE_RUN_NETSIM";
+// how much later than the others a reordered item arrives
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+// One way conditions, applied to each direction of a connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetConditions {
+    pub latency: Duration,
+    // random extra delay, up to this much
+    pub jitter: Duration,
+    // chances from 0 to 1, unreliable items only
+    pub loss: f64,
+    pub duplication: f64,
+    pub reordering: f64
+}
+
+impl NetConditions {
+    // "latency=100,jitter=20,loss=0.05,dup=0.01,reorder=0.02", times in ms, missing keys are 0
+    pub fn parse(spec: &str) -> Result<NetConditions, String> {
+        let mut conditions = NetConditions::default();
+
+        for pair in spec.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(idx) => (&pair[..idx], &pair[idx + 1..]),
+                None => return Err(format!("expected key=value, got `{}`", pair))
+            };
+
+            match key {
+                "latency" => conditions.latency = parse_millis(value)?,
+                "jitter" => conditions.jitter = parse_millis(value)?,
+                "loss" => conditions.loss = parse_chance(value)?,
+                "dup" => conditions.duplication = parse_chance(value)?,
+                "reorder" => conditions.reordering = parse_chance(value)?,
+                _ => return Err(format!("unknown key `{}`", key))
+            }
+        }
+
+        Ok(conditions)
+    }
+
+    // the conditions asked for in the environment, if any
+    pub fn from_env() -> Option<NetConditions> {
+        let spec = env::var(ENV_VAR).ok()?;
+
+        match NetConditions::parse(&spec) {
+            Ok(conditions) => {
+                println!("Simulating network conditions: {:?}", conditions);
+                Some(conditions)
+            },
+            Err(err) => {
+                println!("Ignoring {}: {}", ENV_VAR, err);
+                None
+            }
+        }
+    }
+}
+
+impl Default for NetConditions {
+    fn default() -> NetConditions {
+        NetConditions {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss: 0.,
+            duplication: 0.,
+            reordering: 0.
+        }
+    }
+}
+
+fn parse_millis(value: &str) -> Result<Duration, String> {
+    value.parse::<u64>()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("`{}` is not a number of milliseconds", value))
+}
+
+fn parse_chance(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(chance) if (0. ..=1.).contains(&chance) => Ok(chance),
+        _ => Err(format!("`{}` is not a chance from 0 to 1", value))
+    }
+}
+
+// xorshift64*, good enough to roll dice and needs no dependencies
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // the state must never be 0
+        Rng(seed | 1)
+    }
+
+    // uniform in 0..1
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+}
+
+// One direction of a simulated connection.
+pub struct Lane<T> {
+    conditions: NetConditions,
+    rng: Rng,
+    // items on their way with the time they arrive, the counter keeps items of the same time in order
+    in_flight: Vec<(Instant, u64, T)>,
+    next_id: u64,
+    // reliable items never arrive before the previous one
+    last_reliable: Option<Instant>
+}
+
+impl<T: Clone> Lane<T> {
+    pub fn new(conditions: NetConditions, seed: u64) -> Lane<T> {
+        Lane { conditions, rng: Rng::new(seed), in_flight: vec![], next_id: 0, last_reliable: None }
+    }
+
+    // seeded differently every time
+    pub fn random(conditions: NetConditions) -> Lane<T> {
+        Lane::new(conditions, RandomState::new().build_hasher().finish())
+    }
+
+    pub fn push(&mut self, item: T, reliable: bool, now: Instant) {
+        if reliable {
+            let mut arrival = now + self.delay();
+
+            if let Some(last) = self.last_reliable {
+                arrival = arrival.max(last);
+            }
+
+            self.last_reliable = Some(arrival);
+            self.schedule(arrival, item);
+
+            return;
+        }
+
+        if self.rng.chance(self.conditions.loss) {
+            return;
+        }
+
+        if self.rng.chance(self.conditions.duplication) {
+            let arrival = now + self.delay();
+            self.schedule(arrival, item.clone());
+        }
+
+        let mut arrival = now + self.delay();
+
+        if self.rng.chance(self.conditions.reordering) {
+            arrival += REORDER_DELAY;
+        }
+
+        self.schedule(arrival, item);
+    }
+
+    // items that arrived by `now`, in the order they arrived
+    pub fn pop_due(&mut self, now: Instant) -> Vec<T> {
+        self.in_flight.sort_by_key(|&(arrival, id, _)| (arrival, id));
+
+        let due = self.in_flight.iter().take_while(|&&(arrival, _, _)| arrival <= now).count();
+
+        self.in_flight.drain(..due).map(|(_, _, item)| item).collect()
+    }
+
+    fn delay(&mut self) -> Duration {
+        self.conditions.latency + self.conditions.jitter.mul_f64(self.rng.next_f64())
+    }
+
+    fn schedule(&mut self, arrival: Instant, item: T) {
+        self.in_flight.push((arrival, self.next_id, item));
+        self.next_id += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn conditions(latency: u64, jitter: u64) -> NetConditions {
+        NetConditions { latency: ms(latency), jitter: ms(jitter), ..NetConditions::default() }
+    }
+
+    #[test]
+    fn items_arrive_after_latency() {
+        let start = Instant::now();
+        let mut lane = Lane::new(conditions(100, 0), 1);
+
+        lane.push(1, true, start);
+        lane.push(2, false, start);
+
+        assert!(lane.pop_due(start + ms(99)).is_empty());
+        assert_eq!(lane.pop_due(start + ms(100)), vec![1, 2]);
+    }
+
+    #[test]
+    fn reliable_items_keep_order_under_jitter() {
+        let start = Instant::now();
+        let mut lane = Lane::new(conditions(10, 500), 7);
+
+        for i in 0..100 {
+            lane.push(i, true, start + ms(i));
+        }
+
+        assert_eq!(lane.pop_due(start + ms(1000)), (0..100).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn only_unreliable_items_are_lost() {
+        let start = Instant::now();
+        let mut lane = Lane::new(NetConditions { loss: 1., ..NetConditions::default() }, 3);
+
+        lane.push("reliable", true, start);
+        lane.push("unreliable", false, start);
+
+        assert_eq!(lane.pop_due(start), vec!["reliable"]);
+    }
+
+    #[test]
+    fn unreliable_items_are_duplicated_and_reordered() {
+        let start = Instant::now();
+        let mut lane = Lane::new(NetConditions { duplication: 1., ..NetConditions::default() }, 5);
+
+        lane.push(1, false, start);
+        assert_eq!(lane.pop_due(start), vec![1, 1]);
+
+        let mut lane = Lane::new(NetConditions { reordering: 1., ..NetConditions::default() }, 5);
+        lane.push(1, false, start);
+        lane.push(2, true, start + ms(10));
+
+        assert_eq!(lane.pop_due(start + REORDER_DELAY), vec![2, 1]);
+    }
+
+    #[test]
+    fn conditions_are_parsed() {
+        let parsed = NetConditions::parse("latency=100, jitter=20,loss=0.05,dup=0,reorder=1");
+        let expected = NetConditions { latency: ms(100), jitter: ms(20), loss: 0.05, duplication: 0., reordering: 1. };
+
+        assert_eq!(parsed, Ok(expected));
+        assert_eq!(NetConditions::parse(""), Ok(NetConditions::default()));
+        assert!(NetConditions::parse("loss=2").is_err());
+        assert!(NetConditions::parse("latency").is_err());
+        assert!(NetConditions::parse("bandwidth=10").is_err());
+    }
+}
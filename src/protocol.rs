@@ -0,0 +1,1047 @@
+// Wire format: after the handshake (see `handshake`) every message is a frame
+//
+//   | body_len: u32 | version: u8 | kind: u8 | payload ... |
+//                   \------------- body_len bytes ---------/
+//
+// all integers and floats are big-endian, strings are `u16` length + utf8 bytes.
+//
+// Frames of unreliable messages may go over udp instead (see `Channel`), one per datagram:
+//
+//   | token: u64 | seq: u32 | frame (none in a datagram that only registers the address) |
+//
+// `token` is the one of the client the datagram goes from or to,
+// `seq` grows with every datagram so stale and duplicated ones can be dropped.
+
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read};
+use std::fmt;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use snapshot::{EntityDelta, SnapshotDelta};
+
+pub type NetToken = usize;
+pub type Vec2d = [f64; 2];
+pub type Color = [f32; 4];
+
+pub const PROTOCOL_VERSION: u8 = 11;
+pub const HEADER_LEN: usize = 4;
+pub const MAX_STRING_LEN: usize = u16::MAX as usize;
+// longer chat messages are cut by the server, in chars
+pub const MAX_CHAT_LEN: usize = 200;
+// anything bigger means the stream is out of sync or the peer is not a side-run one
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+pub const DATAGRAM_HEADER_LEN: usize = 12;
+// fits into any path mtu, unreliable messages are much smaller anyway
+pub const MAX_DATAGRAM_LEN: usize = 1200;
+// how many recent sequence numbers the packet loss is measured over
+const LOSS_WINDOW: usize = 100;
+
+const KIND_SPAWN: u8 = 1;
+const KIND_UPDATE_POS: u8 = 2;
+const KIND_DESPAWN: u8 = 3;
+const KIND_ACK: u8 = 4;
+const KIND_SNAPSHOT: u8 = 5;
+const KIND_SNAPSHOT_ACK: u8 = 6;
+const KIND_PING: u8 = 7;
+const KIND_PONG: u8 = 8;
+const KIND_SESSION: u8 = 9;
+const KIND_RESUME: u8 = 10;
+const KIND_RESUMED: u8 = 11;
+const KIND_CHAT: u8 = 12;
+const KIND_JOIN: u8 = 13;
+const KIND_READY: u8 = 14;
+const KIND_START: u8 = 15;
+const KIND_LOBBY: u8 = 16;
+const KIND_SPECTATE: u8 = 17;
+const KIND_NOTICE: u8 = 18;
+const KIND_DISCONNECT: u8 = 19;
+const KIND_MAP: u8 = 20;
+
+// which fields of an entity a snapshot delta carries
+const FIELD_X: u8 = 1;
+const FIELD_Y: u8 = 1 << 1;
+const FIELD_ROTATION: u8 = 1 << 2;
+const FIELD_VELOCITY: u8 = 1 << 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    // a field is present but its value makes no sense
+    MalformedField(&'static str),
+    UnknownEvent(u8),
+    UnsupportedVersion(u8),
+    // a snapshot delta against a snapshot that was not received or is forgotten
+    UnknownBase(u32),
+    // handshake failures
+    BadMagic(u32),
+    VersionMismatch { local: u8, remote: u8 },
+    Rejected,
+    // a frame ended before all of its fields were read
+    Truncated,
+    FrameTooLarge(usize),
+    Disconnected,
+    // nothing was received from the peer for too long
+    TimedOut,
+    Io(io::ErrorKind)
+}
+
+impl ProtocolError {
+    // fatal errors leave the stream unusable, others only spoil a single frame
+    pub fn is_fatal(&self) -> bool {
+        matches!(*self,
+            ProtocolError::FrameTooLarge(_) | ProtocolError::Disconnected | ProtocolError::TimedOut | ProtocolError::Io(_) |
+            ProtocolError::BadMagic(_) | ProtocolError::VersionMismatch { .. } | ProtocolError::Rejected)
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::MalformedField(field) => write!(f, "malformed field `{}`", field),
+            ProtocolError::UnknownEvent(kind) => write!(f, "unknown event kind {}", kind),
+            ProtocolError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}", version),
+            ProtocolError::UnknownBase(tick) => write!(f, "unknown snapshot base tick {}", tick),
+            ProtocolError::BadMagic(_) => write!(f, "peer does not speak the side-run protocol"),
+            ProtocolError::VersionMismatch { local, remote } =>
+                write!(f, "protocol version mismatch: peer speaks {}, we speak {}", remote, local),
+            ProtocolError::Rejected => write!(f, "connection rejected by peer"),
+            ProtocolError::Truncated => write!(f, "truncated message"),
+            ProtocolError::FrameTooLarge(len) => write!(f, "frame of {} bytes exceeds limit", len),
+            ProtocolError::Disconnected => write!(f, "disconnected"),
+            ProtocolError::TimedOut => write!(f, "peer stopped responding"),
+            ProtocolError::Io(kind) => write!(f, "io error: {:?}", kind)
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> ProtocolError {
+        ProtocolError::Io(err.kind())
+    }
+}
+
+// a client waiting in the lobby for the match to start
+#[derive(Debug, Clone, PartialEq)]
+pub struct LobbyPlayer {
+    pub token: NetToken,
+    pub name: String,
+    pub color: Color,
+    pub ready: bool
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Spawn { token: NetToken, name: String, pos: Vec2d, color: Color },
+    // `seq` is the number of the last local input applied to the position
+    // `velocity` in pixels per second, lets others guess where the player goes when updates are late
+    UpdatePos { token: NetToken, seq: u32, pos: Vec2d, rotation: f64, velocity: Vec2d },
+    // the player left the game
+    Despawn { token: NetToken },
+    // authoritative position of the receiver's own player after its input `seq`
+    Ack { seq: u32, pos: Vec2d },
+    // state of the world at a server tick
+    Snapshot(SnapshotDelta),
+    // the client has the snapshot of `tick`, so it can be the base of the next deltas
+    SnapshotAck { tick: u32 },
+    // keep the connection alive and measure its round-trip time; sent over tcp,
+    // so a connection whose udp is filtered on the way is still alive
+    Ping { id: u32 },
+    Pong { id: u32 },
+    // secret the client needs to get its token back after the connection is lost
+    Session { secret: u64 },
+    // asks for the token of a lost connection
+    Resume { token: NetToken, secret: u64 },
+    // the token the client has from now on, its old one if the session was resumed
+    Resumed { token: NetToken },
+    // text said by the player of `token`
+    Chat { token: NetToken, text: String },
+    // enters the lobby
+    Join { name: String, color: Color },
+    Ready { ready: bool },
+    // the host asks to start the match, the server tells everyone in the lobby it started
+    Start,
+    // everyone in the lobby, sent whenever it changes; the host is the one who can start the match
+    Lobby { host: NetToken, players: Vec<LobbyPlayer> },
+    // the client only watches: it gets the world state but never joins the lobby or spawns a player
+    Spectate,
+    // text from the server operator to everyone
+    Notice { text: String },
+    // the server closes the connection for good, the client must not try to resume it
+    Disconnect { reason: String },
+    // the level everyone plays on, see `world::map`; players are gone when it changes
+    Map { name: String }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    // every message arrives, in order
+    Reliable,
+    // may be lost, only the newest state matters
+    Unreliable
+}
+
+impl Message {
+    pub fn channel(&self) -> Channel {
+        match *self {
+            Message::UpdatePos { .. } | Message::Ack { .. } |
+            Message::Snapshot(_) | Message::SnapshotAck { .. } => Channel::Unreliable,
+            Message::Ping { .. } | Message::Pong { .. } |
+            Message::Spawn { .. } | Message::Despawn { .. } |
+            Message::Session { .. } | Message::Resume { .. } | Message::Resumed { .. } |
+            Message::Chat { .. } | Message::Join { .. } | Message::Ready { .. } |
+            Message::Start | Message::Lobby { .. } | Message::Spectate |
+            Message::Notice { .. } | Message::Disconnect { .. } | Message::Map { .. } => Channel::Reliable
+        }
+    }
+}
+
+pub fn encode(message: &Message) -> Vec<u8> {
+    let mut body = vec![PROTOCOL_VERSION];
+
+    match *message {
+        Message::Spawn { token, ref name, pos, color } => {
+            body.push(KIND_SPAWN);
+            body.write_u64::<BigEndian>(token as u64).unwrap();
+            write_string(&mut body, name);
+            write_vec2d(&mut body, pos);
+            body.write_u32::<BigEndian>(pack_color(color)).unwrap();
+        },
+        Message::UpdatePos { token, seq, pos, rotation, velocity } => {
+            body.push(KIND_UPDATE_POS);
+            body.write_u64::<BigEndian>(token as u64).unwrap();
+            body.write_u32::<BigEndian>(seq).unwrap();
+            write_vec2d(&mut body, pos);
+            body.write_f64::<BigEndian>(rotation).unwrap();
+            write_vec2d(&mut body, velocity);
+        },
+        Message::Despawn { token } => {
+            body.push(KIND_DESPAWN);
+            body.write_u64::<BigEndian>(token as u64).unwrap();
+        },
+        Message::Ack { seq, pos } => {
+            body.push(KIND_ACK);
+            body.write_u32::<BigEndian>(seq).unwrap();
+            write_vec2d(&mut body, pos);
+        },
+        Message::Snapshot(ref delta) => {
+            body.push(KIND_SNAPSHOT);
+            write_snapshot(&mut body, delta);
+        },
+        Message::SnapshotAck { tick } => {
+            body.push(KIND_SNAPSHOT_ACK);
+            body.write_u32::<BigEndian>(tick).unwrap();
+        },
+        Message::Ping { id } => {
+            body.push(KIND_PING);
+            body.write_u32::<BigEndian>(id).unwrap();
+        },
+        Message::Pong { id } => {
+            body.push(KIND_PONG);
+            body.write_u32::<BigEndian>(id).unwrap();
+        },
+        Message::Session { secret } => {
+            body.push(KIND_SESSION);
+            body.write_u64::<BigEndian>(secret).unwrap();
+        },
+        Message::Resume { token, secret } => {
+            body.push(KIND_RESUME);
+            body.write_u64::<BigEndian>(token as u64).unwrap();
+            body.write_u64::<BigEndian>(secret).unwrap();
+        },
+        Message::Resumed { token } => {
+            body.push(KIND_RESUMED);
+            body.write_u64::<BigEndian>(token as u64).unwrap();
+        },
+        Message::Chat { token, ref text } => {
+            body.push(KIND_CHAT);
+            body.write_u64::<BigEndian>(token as u64).unwrap();
+            write_string(&mut body, text);
+        },
+        Message::Join { ref name, color } => {
+            body.push(KIND_JOIN);
+            write_string(&mut body, name);
+            body.write_u32::<BigEndian>(pack_color(color)).unwrap();
+        },
+        Message::Ready { ready } => {
+            body.push(KIND_READY);
+            body.push(ready as u8);
+        },
+        Message::Start => body.push(KIND_START),
+        Message::Lobby { host, ref players } => {
+            body.push(KIND_LOBBY);
+            body.write_u64::<BigEndian>(host as u64).unwrap();
+            body.write_u16::<BigEndian>(players.len() as u16).unwrap();
+
+            for player in players.iter() {
+                body.write_u64::<BigEndian>(player.token as u64).unwrap();
+                write_string(&mut body, &player.name);
+                body.write_u32::<BigEndian>(pack_color(player.color)).unwrap();
+                body.push(player.ready as u8);
+            }
+        },
+        Message::Spectate => body.push(KIND_SPECTATE),
+        Message::Notice { ref text } => {
+            body.push(KIND_NOTICE);
+            write_string(&mut body, text);
+        },
+        Message::Disconnect { ref reason } => {
+            body.push(KIND_DISCONNECT);
+            write_string(&mut body, reason);
+        },
+        Message::Map { ref name } => {
+            body.push(KIND_MAP);
+            write_string(&mut body, name);
+        }
+    }
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.write_u32::<BigEndian>(body.len() as u32).unwrap();
+    frame.extend_from_slice(&body);
+
+    frame
+}
+
+// decodes a single complete frame (header included)
+pub fn decode(frame: &[u8]) -> Result<Message, ProtocolError> {
+    if frame.len() < HEADER_LEN {
+        return Err(ProtocolError::Truncated);
+    }
+
+    let body_len = BigEndian::read_u32(&frame[..HEADER_LEN]) as usize;
+
+    if frame.len() - HEADER_LEN < body_len {
+        return Err(ProtocolError::Truncated);
+    }
+
+    let mut reader = Cursor::new(&frame[HEADER_LEN..HEADER_LEN + body_len]);
+    let version = reader.read_u8().map_err(truncated)?;
+
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+
+    let kind = reader.read_u8().map_err(truncated)?;
+
+    let message = match kind {
+        KIND_SPAWN => Message::Spawn {
+            token: read_token(&mut reader)?,
+            name: read_string(&mut reader, "name")?,
+            pos: read_vec2d(&mut reader)?,
+            color: unpack_color(reader.read_u32::<BigEndian>().map_err(truncated)?)
+        },
+        KIND_UPDATE_POS => Message::UpdatePos {
+            token: read_token(&mut reader)?,
+            seq: reader.read_u32::<BigEndian>().map_err(truncated)?,
+            pos: read_vec2d(&mut reader)?,
+            rotation: read_f64(&mut reader, "rotation")?,
+            velocity: read_vec2d(&mut reader)?
+        },
+        KIND_DESPAWN => Message::Despawn {
+            token: read_token(&mut reader)?
+        },
+        KIND_ACK => Message::Ack {
+            seq: reader.read_u32::<BigEndian>().map_err(truncated)?,
+            pos: read_vec2d(&mut reader)?
+        },
+        KIND_SNAPSHOT => Message::Snapshot(read_snapshot(&mut reader)?),
+        KIND_SNAPSHOT_ACK => Message::SnapshotAck {
+            tick: reader.read_u32::<BigEndian>().map_err(truncated)?
+        },
+        KIND_PING => Message::Ping {
+            id: reader.read_u32::<BigEndian>().map_err(truncated)?
+        },
+        KIND_PONG => Message::Pong {
+            id: reader.read_u32::<BigEndian>().map_err(truncated)?
+        },
+        KIND_SESSION => Message::Session {
+            secret: reader.read_u64::<BigEndian>().map_err(truncated)?
+        },
+        KIND_RESUME => Message::Resume {
+            token: read_token(&mut reader)?,
+            secret: reader.read_u64::<BigEndian>().map_err(truncated)?
+        },
+        KIND_RESUMED => Message::Resumed {
+            token: read_token(&mut reader)?
+        },
+        KIND_CHAT => Message::Chat {
+            token: read_token(&mut reader)?,
+            text: read_string(&mut reader, "text")?
+        },
+        KIND_JOIN => Message::Join {
+            name: read_string(&mut reader, "name")?,
+            color: unpack_color(reader.read_u32::<BigEndian>().map_err(truncated)?)
+        },
+        KIND_READY => Message::Ready {
+            ready: read_bool(&mut reader, "ready")?
+        },
+        KIND_START => Message::Start,
+        KIND_LOBBY => read_lobby(&mut reader)?,
+        KIND_SPECTATE => Message::Spectate,
+        KIND_NOTICE => Message::Notice {
+            text: read_string(&mut reader, "text")?
+        },
+        KIND_DISCONNECT => Message::Disconnect {
+            reason: read_string(&mut reader, "reason")?
+        },
+        KIND_MAP => Message::Map {
+            name: read_string(&mut reader, "name")?
+        },
+        _ => return Err(ProtocolError::UnknownEvent(kind))
+    };
+
+    Ok(message)
+}
+
+pub fn encode_datagram(token: NetToken, seq: u32, message: Option<&Message>) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(MAX_DATAGRAM_LEN);
+    datagram.write_u64::<BigEndian>(token as u64).unwrap();
+    datagram.write_u32::<BigEndian>(seq).unwrap();
+
+    if let Some(message) = message {
+        datagram.extend(encode(message));
+    }
+
+    datagram
+}
+
+// returns the token, sequence number and message (if any) of the datagram
+pub fn decode_datagram(datagram: &[u8]) -> Result<(NetToken, u32, Option<Message>), ProtocolError> {
+    if datagram.len() < DATAGRAM_HEADER_LEN {
+        return Err(ProtocolError::Truncated);
+    }
+
+    let token = BigEndian::read_u64(&datagram[..8]) as NetToken;
+    let seq = BigEndian::read_u32(&datagram[8..DATAGRAM_HEADER_LEN]);
+
+    let message = if datagram.len() > DATAGRAM_HEADER_LEN {
+        Some(decode(&datagram[DATAGRAM_HEADER_LEN..])?)
+    } else {
+        None
+    };
+
+    Ok((token, seq, message))
+}
+
+// Sequence numbers of both directions of an unreliable channel.
+pub struct Sequencer {
+    next_send: u32,
+    last_recv: Option<u32>,
+    // whether recent sequence numbers were received, oldest first
+    received: VecDeque<bool>
+}
+
+impl Sequencer {
+    pub fn new() -> Sequencer {
+        Sequencer { next_send: 0, last_recv: None, received: VecDeque::new() }
+    }
+
+    pub fn next_seq(&mut self) -> u32 {
+        let seq = self.next_send;
+        self.next_send = seq.wrapping_add(1);
+
+        seq
+    }
+
+    // false for a datagram that is not newer than the newest one accepted,
+    // numbers are compared so they may wrap around
+    pub fn accept(&mut self, seq: u32) -> bool {
+        match self.last_recv {
+            Some(last) if seq.wrapping_sub(last) as i32 <= 0 => false,
+            last => {
+                // skipped numbers are lost, or so late they would be dropped anyway
+                let skipped = last.map(|last| seq.wrapping_sub(last) - 1).unwrap_or(0);
+
+                for _ in 0..skipped.min(LOSS_WINDOW as u32) {
+                    self.record(false);
+                }
+
+                self.record(true);
+                self.last_recv = Some(seq);
+                true
+            }
+        }
+    }
+
+    // share of recent datagrams that never made it, 0 to 1
+    pub fn packet_loss(&self) -> f64 {
+        if self.received.is_empty() {
+            return 0.;
+        }
+
+        let lost = self.received.iter().filter(|received| !**received).count();
+
+        lost as f64 / self.received.len() as f64
+    }
+
+    fn record(&mut self, received: bool) {
+        if self.received.len() == LOSS_WINDOW {
+            self.received.pop_front();
+        }
+
+        self.received.push_back(received);
+    }
+}
+
+impl Default for Sequencer {
+    fn default() -> Sequencer {
+        Sequencer::new()
+    }
+}
+
+// Accumulates bytes read from a stream and cuts complete frames out of them,
+// so a frame may arrive in several reads and a read may carry several frames.
+pub struct FrameBuffer {
+    buf: Vec<u8>
+}
+
+impl FrameBuffer {
+    pub fn new() -> FrameBuffer {
+        FrameBuffer { buf: Vec::new() }
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    // returns None until the next frame is received completely
+    pub fn next_message(&mut self) -> Option<Result<Message, ProtocolError>> {
+        if self.buf.len() < HEADER_LEN {
+            return None;
+        }
+
+        let body_len = BigEndian::read_u32(&self.buf[..HEADER_LEN]) as usize;
+
+        if body_len > MAX_FRAME_LEN {
+            // there is no way to find the next frame boundary, drop everything
+            self.buf.clear();
+
+            return Some(Err(ProtocolError::FrameTooLarge(body_len)));
+        }
+
+        let frame_len = HEADER_LEN + body_len;
+
+        if self.buf.len() < frame_len {
+            return None;
+        }
+
+        let frame: Vec<u8> = self.buf.drain(..frame_len).collect();
+
+        Some(decode(&frame))
+    }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> FrameBuffer {
+        FrameBuffer::new()
+    }
+}
+
+pub fn pack_color(color: Color) -> u32 {
+    let to_255 = 255f32;
+    let r = color[0] * to_255;
+    let g = color[1] * to_255;
+    let b = color[2] * to_255;
+    let a = color[3] * to_255;
+
+    let rp = (r as u32) << 24;
+    let gp = (g as u32) << 16;
+    let bp = (b as u32) << 8;
+    let ap = a as u32;
+
+    rp | gp | bp | ap
+}
+
+pub fn unpack_color(color_u: u32) -> Color {
+    let inv_255 = 1.0f32 / 255.0f32;
+    let rp = (color_u >> 24) as u8;
+    let gp = (color_u >> 16) as u8;
+    let bp = (color_u >> 8) as u8;
+    let ap = color_u as u8;
+
+    [rp as f32 * inv_255, gp as f32 * inv_255, bp as f32 * inv_255, ap as f32 * inv_255]
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+    // cut overlong strings at a char boundary so the result is still valid utf8
+    let mut len = value.len().min(MAX_STRING_LEN);
+    while !value.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    buf.write_u16::<BigEndian>(len as u16).unwrap();
+    buf.extend_from_slice(&value.as_bytes()[..len]);
+}
+
+fn write_vec2d(buf: &mut Vec<u8>, value: Vec2d) {
+    buf.write_f64::<BigEndian>(value[0]).unwrap();
+    buf.write_f64::<BigEndian>(value[1]).unwrap();
+}
+
+// | tick: u32 | has_base: u8 | base_tick: u32 | changed: u16 | entities ... | removed: u16 | tokens ... |
+// where an entity is | token: u64 | fields: u8 | x: i32 | y: i32 | rotation: u16 | vx: i16 | vy: i16 |
+// without absent fields, the velocity components are one field
+fn write_snapshot(buf: &mut Vec<u8>, delta: &SnapshotDelta) {
+    buf.write_u32::<BigEndian>(delta.tick).unwrap();
+    buf.write_u8(delta.base_tick.is_some() as u8).unwrap();
+    buf.write_u32::<BigEndian>(delta.base_tick.unwrap_or(0)).unwrap();
+
+    buf.write_u16::<BigEndian>(delta.changed.len() as u16).unwrap();
+    for &(token, ref entity) in delta.changed.iter() {
+        let mut fields = 0;
+        if entity.x.is_some() { fields |= FIELD_X; }
+        if entity.y.is_some() { fields |= FIELD_Y; }
+        if entity.rotation.is_some() { fields |= FIELD_ROTATION; }
+        if entity.velocity.is_some() { fields |= FIELD_VELOCITY; }
+
+        buf.write_u64::<BigEndian>(token as u64).unwrap();
+        buf.write_u8(fields).unwrap();
+        if let Some(x) = entity.x { buf.write_i32::<BigEndian>(x).unwrap(); }
+        if let Some(y) = entity.y { buf.write_i32::<BigEndian>(y).unwrap(); }
+        if let Some(rotation) = entity.rotation { buf.write_u16::<BigEndian>(rotation).unwrap(); }
+        if let Some([vx, vy]) = entity.velocity {
+            buf.write_i16::<BigEndian>(vx).unwrap();
+            buf.write_i16::<BigEndian>(vy).unwrap();
+        }
+    }
+
+    buf.write_u16::<BigEndian>(delta.removed.len() as u16).unwrap();
+    for token in delta.removed.iter() {
+        buf.write_u64::<BigEndian>(*token as u64).unwrap();
+    }
+}
+
+fn read_lobby(reader: &mut Cursor<&[u8]>) -> Result<Message, ProtocolError> {
+    let host = read_token(reader)?;
+    let count = reader.read_u16::<BigEndian>().map_err(truncated)?;
+    let mut players = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        players.push(LobbyPlayer {
+            token: read_token(reader)?,
+            name: read_string(reader, "name")?,
+            color: unpack_color(reader.read_u32::<BigEndian>().map_err(truncated)?),
+            ready: read_bool(reader, "ready")?
+        });
+    }
+
+    Ok(Message::Lobby { host, players })
+}
+
+fn read_snapshot(reader: &mut Cursor<&[u8]>) -> Result<SnapshotDelta, ProtocolError> {
+    let tick = reader.read_u32::<BigEndian>().map_err(truncated)?;
+    let has_base = reader.read_u8().map_err(truncated)?;
+    let base_tick = reader.read_u32::<BigEndian>().map_err(truncated)?;
+    let base_tick = match has_base {
+        0 => None,
+        1 => Some(base_tick),
+        _ => return Err(ProtocolError::MalformedField("has_base"))
+    };
+
+    let count = reader.read_u16::<BigEndian>().map_err(truncated)?;
+    let mut changed = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let token = read_token(reader)?;
+        let fields = reader.read_u8().map_err(truncated)?;
+        let x = if fields & FIELD_X != 0 { Some(reader.read_i32::<BigEndian>().map_err(truncated)?) } else { None };
+        let y = if fields & FIELD_Y != 0 { Some(reader.read_i32::<BigEndian>().map_err(truncated)?) } else { None };
+        let rotation = if fields & FIELD_ROTATION != 0 { Some(reader.read_u16::<BigEndian>().map_err(truncated)?) } else { None };
+        let velocity = if fields & FIELD_VELOCITY != 0 {
+            Some([reader.read_i16::<BigEndian>().map_err(truncated)?, reader.read_i16::<BigEndian>().map_err(truncated)?])
+        } else {
+            None
+        };
+
+        changed.push((token, EntityDelta { x, y, rotation, velocity }));
+    }
+
+    let count = reader.read_u16::<BigEndian>().map_err(truncated)?;
+    let mut removed = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        removed.push(read_token(reader)?);
+    }
+
+    Ok(SnapshotDelta { tick, base_tick, changed, removed })
+}
+
+fn truncated(_: io::Error) -> ProtocolError {
+    ProtocolError::Truncated
+}
+
+fn read_token(reader: &mut Cursor<&[u8]>) -> Result<NetToken, ProtocolError> {
+    reader.read_u64::<BigEndian>()
+        .map(|token| token as NetToken)
+        .map_err(truncated)
+}
+
+fn read_bool(reader: &mut Cursor<&[u8]>, field: &'static str) -> Result<bool, ProtocolError> {
+    match reader.read_u8().map_err(truncated)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(ProtocolError::MalformedField(field))
+    }
+}
+
+fn read_string(reader: &mut Cursor<&[u8]>, field: &'static str) -> Result<String, ProtocolError> {
+    let len = reader.read_u16::<BigEndian>().map_err(truncated)? as usize;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).map_err(truncated)?;
+
+    String::from_utf8(buf).map_err(|_| ProtocolError::MalformedField(field))
+}
+
+fn read_f64(reader: &mut Cursor<&[u8]>, field: &'static str) -> Result<f64, ProtocolError> {
+    let value = reader.read_f64::<BigEndian>().map_err(truncated)?;
+
+    // NaN or infinity would poison every position computed from it
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(ProtocolError::MalformedField(field))
+    }
+}
+
+fn read_vec2d(reader: &mut Cursor<&[u8]>) -> Result<Vec2d, ProtocolError> {
+    let x = read_f64(reader, "x")?;
+    let y = read_f64(reader, "y")?;
+
+    Ok([x, y])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawn_round_trip() {
+        let message = Message::Spawn {
+            token: 42,
+            name: "Fr|dge x 3x4".to_string(),
+            pos: [-12.5, 300.25],
+            color: [1., 0., 1., 1.]
+        };
+
+        assert_eq!(decode(&encode(&message)), Ok(message));
+    }
+
+    #[test]
+    fn update_pos_round_trip() {
+        let message = Message::UpdatePos { token: 7, seq: 120, pos: [400., 300.], rotation: -1.5, velocity: [-141.4, 141.4] };
+
+        assert_eq!(decode(&encode(&message)), Ok(message));
+    }
+
+    #[test]
+    fn despawn_round_trip() {
+        let message = Message::Despawn { token: 3 };
+
+        assert_eq!(decode(&encode(&message)), Ok(message));
+    }
+
+    #[test]
+    fn ack_round_trip() {
+        let message = Message::Ack { seq: 0xdead_beef, pos: [-0.5, 1e6] };
+
+        assert_eq!(decode(&encode(&message)), Ok(message));
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let delta = SnapshotDelta {
+            tick: 10,
+            base_tick: Some(8),
+            changed: vec![
+                (1, EntityDelta { x: Some(-5), y: None, rotation: Some(65535), velocity: None }),
+                (2, EntityDelta { x: Some(1), y: Some(2), rotation: Some(3), velocity: Some([-1600, 4]) })
+            ],
+            removed: vec![3, 4]
+        };
+        let full = SnapshotDelta { tick: 1, base_tick: None, changed: vec![], removed: vec![] };
+
+        assert_eq!(decode(&encode(&Message::Snapshot(delta.clone()))), Ok(Message::Snapshot(delta)));
+        assert_eq!(decode(&encode(&Message::Snapshot(full.clone()))), Ok(Message::Snapshot(full)));
+        assert_eq!(decode(&encode(&Message::SnapshotAck { tick: 9 })), Ok(Message::SnapshotAck { tick: 9 }));
+    }
+
+    #[test]
+    fn unchanged_fields_are_not_sent() {
+        let entity = |x| SnapshotDelta { tick: 2, base_tick: Some(1), changed: vec![(1, EntityDelta { x, y: None, rotation: None, velocity: None })], removed: vec![] };
+
+        let full_len = encode(&Message::Snapshot(entity(Some(1)))).len();
+        let delta_len = encode(&Message::Snapshot(entity(None))).len();
+
+        assert_eq!(full_len - delta_len, 4);
+    }
+
+    #[test]
+    fn ping_pong_round_trip() {
+        assert_eq!(decode(&encode(&Message::Ping { id: 17 })), Ok(Message::Ping { id: 17 }));
+        assert_eq!(decode(&encode(&Message::Pong { id: 17 })), Ok(Message::Pong { id: 17 }));
+    }
+
+    #[test]
+    fn session_messages_round_trip() {
+        let messages = vec![
+            Message::Session { secret: 0xdead_beef_cafe_f00d },
+            Message::Resume { token: 4, secret: 1 },
+            Message::Resumed { token: 4 }
+        ];
+
+        for message in messages {
+            assert_eq!(decode(&encode(&message)), Ok(message));
+        }
+    }
+
+    #[test]
+    fn chat_round_trip() {
+        let message = Message::Chat { token: 2, text: "go left, я справа".to_string() };
+
+        assert_eq!(decode(&encode(&message)), Ok(message));
+    }
+
+    #[test]
+    fn admin_messages_round_trip() {
+        let messages = vec![
+            Message::Notice { text: "restart in 5 minutes".to_string() },
+            Message::Disconnect { reason: "kicked by the server".to_string() },
+            Message::Map { name: "arena".to_string() }
+        ];
+
+        for message in messages {
+            assert_eq!(decode(&encode(&message)), Ok(message));
+        }
+    }
+
+    #[test]
+    fn lobby_messages_round_trip() {
+        let players = vec![
+            LobbyPlayer { token: 1, name: "host".to_string(), color: [1., 0., 0., 1.], ready: true },
+            LobbyPlayer { token: 3, name: "guest".to_string(), color: [0., 0., 1., 1.], ready: false }
+        ];
+        let messages = vec![
+            Message::Join { name: "guest".to_string(), color: [0., 0., 1., 1.] },
+            Message::Ready { ready: true },
+            Message::Start,
+            Message::Lobby { host: 1, players },
+            Message::Spectate
+        ];
+
+        for message in messages {
+            assert_eq!(decode(&encode(&message)), Ok(message));
+        }
+    }
+
+    #[test]
+    fn bad_ready_flag_is_malformed() {
+        let mut frame = encode(&Message::Ready { ready: true });
+        let last = frame.len() - 1;
+        frame[last] = 2;
+
+        assert_eq!(decode(&frame), Err(ProtocolError::MalformedField("ready")));
+    }
+
+    #[test]
+    fn long_name_round_trip() {
+        let name: String = "name|x".repeat(100);
+        let message = Message::Spawn { token: 1, name, pos: [0., 0.], color: [0., 0., 0., 1.] };
+
+        assert_eq!(decode(&encode(&message)), Ok(message));
+    }
+
+    #[test]
+    fn overlong_name_is_cut() {
+        let name: String = "й".repeat(MAX_STRING_LEN);
+        let message = Message::Spawn { token: 1, name: name.clone(), pos: [0., 0.], color: [0., 0., 0., 1.] };
+
+        match decode(&encode(&message)) {
+            Ok(Message::Spawn { name: decoded, .. }) => {
+                assert!(decoded.len() <= MAX_STRING_LEN);
+                assert!(name.starts_with(&decoded));
+            },
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn frame_length_matches_header() {
+        let frame = encode(&Message::UpdatePos { token: 7, seq: 7, pos: [1., 2.], rotation: 0., velocity: [0., 0.] });
+        let body_len = BigEndian::read_u32(&frame[..HEADER_LEN]) as usize;
+
+        assert_eq!(frame.len(), HEADER_LEN + body_len);
+    }
+
+    #[test]
+    fn truncated_frame_is_rejected() {
+        let frame = encode(&Message::UpdatePos { token: 7, seq: 7, pos: [1., 2.], rotation: 0., velocity: [0., 0.] });
+
+        assert_eq!(decode(&frame[..frame.len() - 1]), Err(ProtocolError::Truncated));
+        assert_eq!(decode(&frame[..2]), Err(ProtocolError::Truncated));
+    }
+
+    #[test]
+    fn short_body_is_truncated() {
+        let mut frame = encode(&Message::UpdatePos { token: 7, seq: 7, pos: [1., 2.], rotation: 0., velocity: [0., 0.] });
+        // header claims the frame ends right after the token
+        frame.truncate(HEADER_LEN + 2 + 8);
+        BigEndian::write_u32(&mut frame[..HEADER_LEN], 2 + 8);
+
+        assert_eq!(decode(&frame), Err(ProtocolError::Truncated));
+    }
+
+    #[test]
+    fn non_finite_position_is_malformed() {
+        let frame = encode(&Message::UpdatePos { token: 7, seq: 7, pos: [f64::NAN, 2.], rotation: 0., velocity: [0., 0.] });
+
+        assert_eq!(decode(&frame), Err(ProtocolError::MalformedField("x")));
+    }
+
+    #[test]
+    fn invalid_utf8_name_is_malformed() {
+        let mut frame = encode(&Message::Spawn { token: 1, name: "ab".to_string(), pos: [0., 0.], color: [0., 0., 0., 1.] });
+        // version, kind, token, name length
+        frame[HEADER_LEN + 2 + 8 + 2] = 0xff;
+
+        assert_eq!(decode(&frame), Err(ProtocolError::MalformedField("name")));
+    }
+
+    #[test]
+    fn unknown_kind_is_rejected() {
+        let mut frame = encode(&Message::UpdatePos { token: 7, seq: 7, pos: [1., 2.], rotation: 0., velocity: [0., 0.] });
+        frame[HEADER_LEN + 1] = 255;
+
+        assert_eq!(decode(&frame), Err(ProtocolError::UnknownEvent(255)));
+    }
+
+    #[test]
+    fn other_version_is_rejected() {
+        let mut frame = encode(&Message::UpdatePos { token: 7, seq: 7, pos: [1., 2.], rotation: 0., velocity: [0., 0.] });
+        frame[HEADER_LEN] = PROTOCOL_VERSION + 1;
+
+        assert_eq!(decode(&frame), Err(ProtocolError::UnsupportedVersion(PROTOCOL_VERSION + 1)));
+    }
+
+    #[test]
+    fn datagram_round_trip() {
+        let message = Message::UpdatePos { token: 7, seq: 3, pos: [1., 2.], rotation: 0.5, velocity: [0., 0.] };
+
+        assert_eq!(decode_datagram(&encode_datagram(7, 42, Some(&message))), Ok((7, 42, Some(message))));
+        assert_eq!(decode_datagram(&encode_datagram(7, 43, None)), Ok((7, 43, None)));
+        assert_eq!(decode_datagram(&[0u8; DATAGRAM_HEADER_LEN - 1]), Err(ProtocolError::Truncated));
+    }
+
+    #[test]
+    fn only_position_messages_are_unreliable() {
+        assert_eq!(Message::UpdatePos { token: 1, seq: 1, pos: [0., 0.], rotation: 0., velocity: [0., 0.] }.channel(), Channel::Unreliable);
+        assert_eq!(Message::Ack { seq: 1, pos: [0., 0.] }.channel(), Channel::Unreliable);
+        assert_eq!(Message::Despawn { token: 1 }.channel(), Channel::Reliable);
+        assert_eq!(Message::Ping { id: 1 }.channel(), Channel::Reliable);
+    }
+
+    #[test]
+    fn sequencer_drops_stale_datagrams() {
+        let mut sequencer = Sequencer::new();
+
+        assert!(sequencer.accept(5));
+        assert!(!sequencer.accept(5));
+        assert!(!sequencer.accept(3));
+        assert!(sequencer.accept(8));
+    }
+
+    #[test]
+    fn sequencer_survives_wrap_around() {
+        let mut sequencer = Sequencer::new();
+
+        assert!(sequencer.accept(u32::MAX - 1));
+        assert!(sequencer.accept(1));
+        assert!(!sequencer.accept(u32::MAX));
+    }
+
+    #[test]
+    fn sequencer_counts_skipped_numbers_as_lost() {
+        let mut sequencer = Sequencer::new();
+        assert_eq!(sequencer.packet_loss(), 0.);
+
+        sequencer.accept(0);
+        sequencer.accept(1);
+        assert_eq!(sequencer.packet_loss(), 0.);
+
+        sequencer.accept(4);
+        assert_eq!(sequencer.packet_loss(), 0.4);
+
+        // a late one is dropped, it stays lost
+        assert!(!sequencer.accept(3));
+        assert_eq!(sequencer.packet_loss(), 0.4);
+    }
+
+    #[test]
+    fn sequencer_loss_is_measured_over_recent_numbers() {
+        let mut sequencer = Sequencer::new();
+
+        sequencer.accept(0);
+        sequencer.accept(1000);
+        assert_eq!(sequencer.packet_loss(), 0.99);
+
+        for seq in 1001..1100 {
+            sequencer.accept(seq);
+        }
+        assert_eq!(sequencer.packet_loss(), 0.);
+    }
+
+    #[test]
+    fn frame_buffer_waits_for_complete_frame() {
+        let message = Message::UpdatePos { token: 7, seq: 7, pos: [1., 2.], rotation: 0.5, velocity: [0., 0.] };
+        let frame = encode(&message);
+        let mut buffer = FrameBuffer::new();
+
+        for byte in frame.iter() {
+            assert!(buffer.next_message().is_none());
+            buffer.extend(&[*byte]);
+        }
+
+        assert_eq!(buffer.next_message(), Some(Ok(message)));
+        assert!(buffer.next_message().is_none());
+    }
+
+    #[test]
+    fn frame_buffer_splits_coalesced_frames() {
+        let first = Message::Spawn { token: 1, name: "first".to_string(), pos: [0., 0.], color: [1., 1., 1., 1.] };
+        let second = Message::UpdatePos { token: 1, seq: 1, pos: [5., 5.], rotation: 0., velocity: [0., 0.] };
+        let third = Message::UpdatePos { token: 2, seq: 2, pos: [6., 6.], rotation: 1., velocity: [0., 0.] };
+        let mut stream = encode(&first);
+        stream.extend(encode(&second));
+        stream.extend(encode(&third));
+
+        // the last frame straddles two reads
+        let (head, tail) = stream.split_at(stream.len() - 3);
+        let mut buffer = FrameBuffer::new();
+        buffer.extend(head);
+
+        assert_eq!(buffer.next_message(), Some(Ok(first)));
+        assert_eq!(buffer.next_message(), Some(Ok(second)));
+        assert!(buffer.next_message().is_none());
+
+        buffer.extend(tail);
+
+        assert_eq!(buffer.next_message(), Some(Ok(third)));
+        assert!(buffer.next_message().is_none());
+    }
+
+    #[test]
+    fn frame_buffer_skips_undecodable_frame() {
+        let mut bad = encode(&Message::UpdatePos { token: 7, seq: 7, pos: [1., 2.], rotation: 0., velocity: [0., 0.] });
+        bad[HEADER_LEN + 1] = 255;
+        let good = Message::UpdatePos { token: 8, seq: 8, pos: [3., 4.], rotation: 0., velocity: [0., 0.] };
+        let mut buffer = FrameBuffer::new();
+        buffer.extend(&bad);
+        buffer.extend(&encode(&good));
+
+        assert_eq!(buffer.next_message(), Some(Err(ProtocolError::UnknownEvent(255))));
+        assert_eq!(buffer.next_message(), Some(Ok(good)));
+    }
+
+    #[test]
+    fn frame_buffer_rejects_oversized_frame() {
+        let mut buffer = FrameBuffer::new();
+        buffer.extend(&[0xff, 0xff, 0xff, 0xff, 1, 2]);
+
+        assert_eq!(buffer.next_message(), Some(Err(ProtocolError::FrameTooLarge(0xffff_ffff))));
+        assert!(buffer.next_message().is_none());
+    }
+}
@@ -3,17 +3,6 @@ use piston_window::math::*;
 
 pub type GameResult<T> = Result<T, String>;
 
-pub trait Movable {
-    fn translate_by_direction(&mut self, direction: Direction, units: f64);
-}
-
-impl Movable for Vec2d {
-    fn translate_by_direction(&mut self, direction: Direction, units: f64) {
-        let translate_vec = mul_scalar(Vec2d::from(direction), units);
-        *self = add(translate_vec, self.clone());
-    }
-}
-
 // TODO: forward, back, left, right
 #[derive(Clone)]
 pub enum Direction {
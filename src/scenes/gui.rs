@@ -0,0 +1,69 @@
+use conrod;
+use find_folder;
+use piston_window::*;
+use asset_manager::AssetManager;
+
+pub const UI_WIDTH: f64 = 800.;
+pub const UI_HEIGHT: f64 = 600.;
+const SCALE_TOLERANCE: f32 = 0.1;
+const POSITION_TOLERANCE: f32 = 0.1;
+
+// conrod ui of the window size with the game font loaded
+pub fn new_ui() -> conrod::Ui {
+    let mut ui = conrod::UiBuilder::new([UI_WIDTH, UI_HEIGHT]).build();
+
+    let assets = find_folder::Search::KidsThenParents(3, 5).for_folder("assets").unwrap();
+    let font_path = assets.join("fonts/Terminus.ttf");
+    ui.fonts.insert_from_file(font_path).unwrap();
+
+    ui
+}
+
+pub fn new_glyph_cache() -> conrod::text::GlyphCache {
+    conrod::text::GlyphCache::new(UI_WIDTH as u32, UI_HEIGHT as u32, SCALE_TOLERANCE, POSITION_TOLERANCE)
+}
+
+pub fn handle_event(ui: &mut conrod::Ui, event: Event) {
+    if let Some(e) = conrod::backend::piston::event::convert(event, UI_WIDTH, UI_HEIGHT) {
+        ui.handle_event(e);
+    }
+}
+
+pub fn draw_primitives(
+    primitives: conrod::render::Primitives,
+    ctx: Context,
+    graphics: &mut G2d,
+    asset_manager: &mut AssetManager,
+    glyph_cache: &mut conrod::text::GlyphCache,
+    image_map: &conrod::image::Map<G2dTexture>
+) {
+    // A function used for caching glyphs to the texture cache.
+    fn cache_queued_glyphs(graphics: &mut G2d, cache: &mut G2dTexture, rect: conrod::text::rt::Rect<u32>, data: &[u8]) {
+        let mut text_vertex_data = Vec::new();
+        let offset = [rect.min.x, rect.min.y];
+        let size = [rect.width(), rect.height()];
+        let format = texture::Format::Rgba8;
+        let encoder = &mut graphics.encoder;
+        text_vertex_data.clear();
+        text_vertex_data.extend(data.iter().flat_map(|&b| vec![255, 255, 255, b]));
+
+        texture::UpdateTexture::update(cache, encoder, format, &text_vertex_data[..], offset, size)
+            .expect("failed to update texture")
+    }
+
+    // Specify how to get the drawable texture from the image. In this case, the image
+    // *is* the texture.
+    fn texture_from_image<T>(img: &T) -> &T { img }
+
+    // Draw the conrod `render::Primitives`.
+    conrod::backend::piston::draw::primitives(
+        primitives,
+        ctx,
+        graphics,
+        asset_manager.get_texture_mut("ui_cache").unwrap(),
+        glyph_cache,
+        image_map,
+        cache_queued_glyphs,
+        texture_from_image
+    );
+}
@@ -0,0 +1,285 @@
+use scenes::common::*;
+use scenes::scene::{Scene, BaseSwitcher, Switcher};
+use scenes::play::{Play, PlayerConfig};
+use scenes::menu::Menu;
+use scenes::gui;
+use connection::{Connection, NetConfig};
+use protocol::{LobbyPlayer, Message, NetToken};
+use server::ServerHandle;
+use conrod::{self, widget, Colorable, Positionable, Widget, Labelable, Sizeable, color};
+use piston_window::*;
+use asset_manager::AssetManager;
+
+widget_ids!(struct Ids {
+    canvas,
+    title,
+    player_colors[],
+    player_names[],
+    ready_button,
+    start_button,
+    leave_button,
+    status
+});
+
+// Players gather here until the host starts the match, then everyone goes to `Play` at once.
+pub struct Lobby {
+    switcher: BaseSwitcher,
+    ui: conrod::Ui,
+    ids: Ids,
+    image_map: conrod::image::Map<G2dTexture>,
+    glyph_cache: conrod::text::GlyphCache,
+    // taken by `Play` when the match starts
+    connection: Option<Connection>,
+    player_config: PlayerConfig,
+    net_config: NetConfig,
+    // the game this client hosts, it ends when the client leaves
+    hosted: Option<ServerHandle>,
+    host: Option<NetToken>,
+    players: Vec<LobbyPlayer>,
+    ready: bool,
+    // game events received before the start, `Play` needs them to know who is already playing
+    backlog: Vec<Message>,
+    status_text: Option<String>
+}
+
+impl Lobby {
+    pub fn new(mut connection: Connection, player_config: PlayerConfig, net_config: NetConfig, hosted: Option<ServerHandle>) -> Lobby {
+        let mut ui = gui::new_ui();
+        let ids = Ids::new(ui.widget_id_generator());
+
+        let join = Message::Join { name: player_config.name.clone(), color: player_config.color };
+        let status_text = match connection.send(&join) {
+            Ok(_) => Some("Joining the lobby...".to_string()),
+            Err(err) => Some(format!("Failed to join the lobby: {}", err))
+        };
+
+        Lobby {
+            switcher: BaseSwitcher::new(None),
+            ui,
+            ids,
+            image_map: conrod::image::Map::<G2dTexture>::new(),
+            glyph_cache: gui::new_glyph_cache(),
+            connection: Some(connection),
+            player_config,
+            net_config,
+            hosted,
+            host: None,
+            players: vec![],
+            ready: false,
+            backlog: vec![],
+            status_text
+        }
+    }
+
+    fn handle_events(&mut self) {
+        let results = match self.connection {
+            Some(ref mut connection) => connection.poll_messages(),
+            None => return
+        };
+
+        // the rest of the batch goes to the backlog as well, so the match starts after it
+        let mut started = false;
+
+        for result in results {
+            match result {
+                Ok(Message::Lobby { host, players }) => {
+                    self.host = Some(host);
+                    self.players = players;
+                    self.status_text = None;
+                },
+                Ok(Message::Start) => started = true,
+                Ok(Message::Notice { text }) => self.status_text = Some(format!("server: {}", text)),
+                Ok(Message::Disconnect { reason }) => self.leave(format!("Disconnected by server: {}", reason)),
+                // a newer one comes every tick
+                Ok(Message::Snapshot(_)) | Ok(Message::Ack { .. }) => (),
+                Ok(message) => self.backlog.push(message),
+                Err(ref err) if !err.is_fatal() => println!("Dropped bad packet: {}", err),
+                Err(err) => self.leave(format!("Disconnected from server: {}", err))
+            }
+        }
+
+        if started {
+            self.start();
+        }
+    }
+
+    fn start(&mut self) {
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => return
+        };
+
+        println!("The match started");
+
+        let mut play = Play::new(Some(connection), self.player_config.clone(), self.net_config.clone(), self.hosted.take());
+
+        for message in self.backlog.drain(..) {
+            play.handle_message(message);
+        }
+
+        self.switcher.set_next(Some(Box::new(play)));
+    }
+
+    fn leave(&mut self, status: String) {
+        let host = match self.connection.take() {
+            Some(connection) => connection.addr.to_string(),
+            None => return
+        };
+
+        let menu = Menu::with_status(self.player_config.clone(), host, status);
+        self.switcher.set_next(Some(Box::new(menu)));
+    }
+
+    fn send(&mut self, message: &Message) {
+        let result = match self.connection {
+            Some(ref mut connection) => connection.send(message),
+            None => return
+        };
+
+        if let Err(err) = result {
+            self.status_text = Some(format!("Failed to send: {}", err));
+        }
+    }
+
+    fn is_host(&self) -> bool {
+        match self.connection {
+            Some(ref connection) => self.host == Some(connection.token),
+            None => false
+        }
+    }
+}
+
+impl Scene for Lobby {
+    fn handle_event(&mut self, event: Event) {
+        gui::handle_event(&mut self.ui, event);
+    }
+
+    fn update(&mut self, _dt: f64) -> GameResult<()> {
+        self.handle_events();
+
+        if self.connection.is_none() {
+            return Ok(());
+        }
+
+        // one row of widgets per player
+        {
+            let count = self.players.len();
+            let id_generator = &mut self.ui.widget_id_generator();
+
+            self.ids.player_colors.resize(count, id_generator);
+            self.ids.player_names.resize(count, id_generator);
+        }
+
+        let is_host = self.is_host();
+        let everyone_ready = self.players.iter().all(|player| player.ready);
+        let mut clicked_ready = false;
+        let mut clicked_start = false;
+        let mut clicked_leave = false;
+
+        {
+            let ui = &mut self.ui.set_widgets();
+
+            widget::Canvas::new()
+                .color(conrod::color::DARK_CHARCOAL)
+                .set(self.ids.canvas, ui);
+
+            widget::Text::new("LOBBY")
+                .mid_top_with_margin_on(self.ids.canvas, 40.)
+                .color(conrod::color::WHITE)
+                .font_size(32)
+                .set(self.ids.title, ui);
+
+            let mut prev = self.ids.title;
+
+            for (i, player) in self.players.iter().enumerate() {
+                let [r, g, b, a] = player.color;
+                let color_id = self.ids.player_colors[i];
+
+                widget::Rectangle::fill_with([20., 20.], color::Color::from(color::Rgba(r, g, b, a)))
+                    .down_from(prev, 15.)
+                    .x_place_on(self.ids.canvas, conrod::position::Place::Start(Some(250.)))
+                    .set(color_id, ui);
+
+                let host_mark = if self.host == Some(player.token) { " (host)" } else { "" };
+                let ready_mark = if player.ready { "ready" } else { "not ready" };
+                let label = format!("{}{} - {}", player.name, host_mark, ready_mark);
+
+                widget::Text::new(&label)
+                    .right_from(color_id, 10.)
+                    .color(if player.ready { conrod::color::LIGHT_GREEN } else { conrod::color::WHITE })
+                    .font_size(16)
+                    .set(self.ids.player_names[i], ui);
+
+                prev = color_id;
+            }
+
+            for _press in widget::Button::new()
+                .label(if self.ready { "not ready" } else { "ready" })
+                .w_h(150., 40.)
+                .mid_bottom_with_margin_on(self.ids.canvas, 100.)
+                .set(self.ids.ready_button, ui)
+                {
+                    clicked_ready = true;
+                }
+
+            if is_host {
+                for _press in widget::Button::new()
+                    .label(if everyone_ready { "start" } else { "waiting for players" })
+                    .w_h(200., 40.)
+                    .right_from(self.ids.ready_button, 10.)
+                    .set(self.ids.start_button, ui)
+                    {
+                        clicked_start = everyone_ready;
+                    }
+            }
+
+            for _press in widget::Button::new()
+                .label("leave")
+                .w_h(150., 40.)
+                .left_from(self.ids.ready_button, 10.)
+                .set(self.ids.leave_button, ui)
+                {
+                    clicked_leave = true;
+                }
+
+            if let Some(ref status) = self.status_text {
+                widget::Text::new(status)
+                    .center_justify()
+                    .align_middle_x_of(self.ids.canvas)
+                    .down_from(self.ids.ready_button, 10.)
+                    .color(conrod::color::LIGHT_RED)
+                    .font_size(14)
+                    .set(self.ids.status, ui);
+            }
+        }
+
+        if clicked_ready {
+            self.ready = !self.ready;
+            let ready = self.ready;
+            self.send(&Message::Ready { ready });
+        }
+
+        // the server starts the match for everyone, this client too
+        if clicked_start {
+            self.send(&Message::Start);
+        }
+
+        if clicked_leave {
+            self.leave("Left the lobby".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn switcher(&mut self) -> &mut dyn Switcher {
+        &mut self.switcher
+    }
+
+    fn draw(&mut self, ctx: &mut Context, graphics: &mut G2d, asset_manager: &mut AssetManager) -> GameResult<()> {
+        if let Some(primitives) = self.ui.draw_if_changed() {
+            gui::draw_primitives(primitives, *ctx, graphics, asset_manager, &mut self.glyph_cache, &self.image_map);
+        }
+
+        Ok(())
+    }
+}
@@ -1,7 +1,12 @@
 use scenes::common::*;
 use scenes::scene::{Scene, SceneInstance, BaseSwitcher, Switcher};
+use scenes::gui;
 use scenes::play::{Play, PlayerConfig};
-use find_folder;
+use scenes::lobby::Lobby;
+use connection::{ConnectEvent, Connector, NetConfig};
+use discovery::{Browser, DISCOVERY_PORT};
+use netsim::NetConditions;
+use server::{self, ServerHandle};
 use conrod::{self, widget, Colorable, Positionable, Widget, Labelable, Sizeable, color};
 use piston_window::*;
 use asset_manager::AssetManager;
@@ -9,13 +14,18 @@ use asset_manager::AssetManager;
 widget_ids!(struct Ids {
     text,
     button,
+    host_button,
+    watch_button,
     input_host,
     input_name,
     canvas,
     slider_r,
     slider_g,
     slider_b,
-    color_box
+    color_box,
+    status,
+    lan_title,
+    lan_servers[]
 });
 
 pub struct Menu {
@@ -26,165 +36,325 @@ pub struct Menu {
     glyph_cache: conrod::text::GlyphCache,
     input_host_text: String,
     input_name_text: String,
-    color: color::Color
+    color: color::Color,
+    // progress of the connection attempt or why the previous one failed
+    status_text: Option<String>,
+    net_config: NetConfig,
+    connector: Option<Connector>,
+    // the connection being made is for watching the game, not playing it
+    spectate: bool,
+    // the game this client hosts, goes to the lobby with the connection to it
+    hosted: Option<ServerHandle>,
+    // finds servers on the local network, None if there is no network to look at
+    browser: Option<Browser>
 }
 
 impl Menu {
     pub fn new() -> Menu {
-        let mut ui = conrod::UiBuilder::new([800., 600.]).build();
-
-        let assets = find_folder::Search::KidsThenParents(3, 5).for_folder("assets").unwrap();
-        let font_path = assets.join("fonts/Terminus.ttf");
-        ui.fonts.insert_from_file(font_path).unwrap();
-
-        const WIDTH: u32 = 800;
-        const HEIGHT: u32 = 600;
-        const SCALE_TOLERANCE: f32 = 0.1;
-        const POSITION_TOLERANCE: f32 = 0.1;
+        let mut ui = gui::new_ui();
 
         Menu {
             switcher: BaseSwitcher::new(None),
             ids: Ids::new(ui.widget_id_generator()),
-            ui: ui,
+            ui,
             image_map: conrod::image::Map::<G2dTexture>::new(),
-            glyph_cache: conrod::text::GlyphCache::new(WIDTH, HEIGHT, SCALE_TOLERANCE, POSITION_TOLERANCE),
+            glyph_cache: gui::new_glyph_cache(),
             input_host_text: String::from("127.0.0.1:7001"),
             input_name_text: String::from("Fridge"),
-            color: color::Color::from(color::Rgba(1., 0., 0., 1.))
+            color: color::Color::from(color::Rgba(1., 0., 0., 1.)),
+            status_text: None,
+            // debug setting, see netsim::ENV_VAR
+            net_config: NetConfig { simulation: NetConditions::from_env(), ..NetConfig::default() },
+            connector: None,
+            spectate: false,
+            hosted: None,
+            browser: match Browser::new() {
+                Ok(browser) => Some(browser),
+                Err(err) => {
+                    println!("LAN discovery is off: {}", err);
+                    None
+                }
+            }
+        }
+    }
+
+    // menu to come back to with previous inputs kept
+    pub fn with_status(player_config: PlayerConfig, host: String, status: String) -> Menu {
+        let mut menu = Menu::new();
+        let [r, g, b, a] = player_config.color;
+
+        menu.input_name_text = player_config.name;
+        menu.input_host_text = host;
+        menu.color = color::Color::from(color::Rgba(r, g, b, a));
+        menu.status_text = Some(status);
+
+        menu
+    }
+
+    fn player_config(&self) -> PlayerConfig {
+        PlayerConfig {
+            name: self.input_name_text.clone(),
+            color: self.color.to_fsa()
+        }
+    }
+
+    // port of the host input, the default one if the input has none
+    fn host_port(&self) -> Result<u16, String> {
+        let text = self.input_host_text.trim();
+
+        if text.is_empty() {
+            return Ok(DISCOVERY_PORT);
+        }
+
+        // the port of "address:port" or just a port
+        let port = text.rsplit(':').next().unwrap_or(text);
+
+        port.parse().map_err(|_| format!("Bad port to host on: `{}`", port))
+    }
+
+    // starts a server in the background and connects to it like to any other
+    fn host(&mut self) {
+        let port = match self.host_port() {
+            Ok(port) => port,
+            Err(err) => {
+                self.status_text = Some(err);
+                return;
+            }
+        };
+
+        let name = format!("{}'s game", self.input_name_text);
+
+        match server::host(port, name) {
+            Ok(hosted) => {
+                println!("Hosting a game on {}", hosted.addr());
+
+                self.input_host_text = hosted.loopback_addr().to_string();
+                self.spectate = false;
+                self.connector = Some(Connector::start(self.input_host_text.clone(), &self.net_config));
+                self.status_text = Some(format!("Hosting on port {}, connecting...", port));
+                self.hosted = Some(hosted);
+            },
+            Err(err) => self.status_text = Some(format!("Failed to host on port {}: {}", port, err))
+        }
+    }
+
+    fn poll_connector(&mut self) {
+        let event = match self.connector {
+            Some(ref mut connector) => connector.poll(),
+            None => None
+        };
+
+        match event {
+            Some(ConnectEvent::Attempt(attempt)) => {
+                self.status_text = Some(format!(
+                    "Connecting to {} (attempt {} of {})...",
+                    self.input_host_text, attempt, self.net_config.connect_attempts
+                ));
+            },
+            Some(ConnectEvent::Connected(connection)) => {
+                self.connector = None;
+                self.status_text = None;
+
+                // spectators go straight to the game, the lobby is for those who play
+                let next: SceneInstance = if self.spectate {
+                    Box::new(Play::spectate(*connection, self.player_config(), self.net_config.clone()))
+                } else {
+                    Box::new(Lobby::new(*connection, self.player_config(), self.net_config.clone(), self.hosted.take()))
+                };
+
+                self.switcher.set_next(Some(next));
+            },
+            Some(ConnectEvent::Failed(err)) => {
+                self.connector = None;
+                self.hosted = None;
+                self.status_text = Some(format!("Failed to connect: {}", err));
+            },
+            None => ()
         }
     }
 }
 
 impl Scene for Menu {
     fn handle_event(&mut self, event: Event) {
-        let size = (800. as conrod::Scalar,  600. as conrod::Scalar);
-
-        if let Some(e) = conrod::backend::piston::event::convert(event, size.0, size.1) {
-            self.ui.handle_event(e);
-        }
+        gui::handle_event(&mut self.ui, event);
     }
 
     fn update(&mut self, _dt: f64) -> GameResult<()> {
-        // Set the widgets.
-        let ui = &mut self.ui.set_widgets();
-
-        widget::Canvas::new()
-            .color(conrod::color::DARK_CHARCOAL)
-            .set(self.ids.canvas, ui);
-
-        widget::BorderedRectangle::new([100., 100.])
-            .mid_top()
-            .y_place(conrod::position::Place::End(Some(20.)))
-            .with_style(conrod::widget::bordered_rectangle::Style {
-                color: Some(self.color),
-                border: Some(3.),
-                border_color: Some(conrod::color::BLACK)
-            })
-            .set(self.ids.color_box, ui);
+        self.poll_connector();
+
+        let servers = match self.browser {
+            Some(ref mut browser) => {
+                browser.poll();
+                browser.servers()
+            },
+            None => vec![]
+        };
+
+        {
+            let id_generator = &mut self.ui.widget_id_generator();
+            self.ids.lan_servers.resize(servers.len(), id_generator);
+        }
+
+        let mut clicked_host = false;
 
         {
-            for val in widget::Slider::new(self.color.red(), 0., 1.)
-                .left_from(self.ids.color_box, 10.)
-                .set(self.ids.slider_r, ui)
+            // Set the widgets.
+            let ui = &mut self.ui.set_widgets();
+
+            widget::Canvas::new()
+                .color(conrod::color::DARK_CHARCOAL)
+                .set(self.ids.canvas, ui);
+
+            widget::BorderedRectangle::new([100., 100.])
+                .mid_top()
+                .y_place(conrod::position::Place::End(Some(20.)))
+                .with_style(conrod::widget::bordered_rectangle::Style {
+                    color: Some(self.color),
+                    border: Some(3.),
+                    border_color: Some(conrod::color::BLACK)
+                })
+                .set(self.ids.color_box, ui);
+
+            {
+                if let Some(val) = widget::Slider::new(self.color.red(), 0., 1.)
+                    .left_from(self.ids.color_box, 10.)
+                    .set(self.ids.slider_r, ui)
+                    {
+                        self.color.set_red(val);
+                    }
+
+                if let Some(val) = widget::Slider::new(self.color.green(), 0., 1.)
+                    .down_from(self.ids.color_box, 10.)
+                    .set(self.ids.slider_g, ui)
+                    {
+                        self.color.set_green(val);
+                    }
+
+                if let Some(val) = widget::Slider::new(self.color.blue(), 0., 1.)
+                    .right_from(self.ids.color_box, 10.)
+                    .set(self.ids.slider_b, ui)
+                    {
+                        self.color.set_blue(val);
+                    }
+            }
+
+            widget::Text::new("SIDE_RUN")
+                .center_justify()
+                .middle_of(ui.window)
+                .color(conrod::color::WHITE)
+                .font_size(32)
+                .set(self.ids.text, ui);
+
+            if let Some(edit) = widget::TextEdit::new(&self.input_name_text)
+                .center_justify()
+                .down_from(self.ids.text, 20.)
+                .set(self.ids.input_name, ui)
                 {
-                    self.color.set_red(val);
+                    self.input_name_text = edit;
                 }
 
-            for val in widget::Slider::new(self.color.green(), 0., 1.)
-                .down_from(self.ids.color_box, 10.)
-                .set(self.ids.slider_g, ui)
+            if let Some(edit) = widget::TextEdit::new(&self.input_host_text)
+                .center_justify()
+                .w(255.)
+                .mid_bottom()
+                .y_place(conrod::position::Place::Start(Some(100.)))
+                .set(self.ids.input_host, ui)
                 {
-                    self.color.set_green(val);
+                    self.input_host_text = edit;
                 }
 
-            for val in widget::Slider::new(self.color.blue(), 0., 1.)
-                .right_from(self.ids.color_box, 10.)
-                .set(self.ids.slider_b, ui)
+            let connecting = self.connector.is_some();
+
+            for _press in widget::Button::new()
+                .align_middle_x()
+                .label(if connecting { "cancel" } else { "start" })
+                .down_from(self.ids.input_host, 10.0)
+                .set(self.ids.button, ui)
                 {
-                    self.color.set_blue(val);
+                    if connecting {
+                        self.connector = None;
+                        self.hosted = None;
+                        self.status_text = Some("Connection cancelled".to_string());
+                    } else {
+                        self.connector = Some(Connector::start(self.input_host_text.clone(), &self.net_config));
+                        self.spectate = false;
+                        self.status_text = Some(format!("Connecting to {}...", self.input_host_text));
+                    }
                 }
-        }
 
-        widget::Text::new("SIDE_RUN")
-            .center_justify()
-            .middle_of(ui.window)
-            .color(conrod::color::WHITE)
-            .font_size(32)
-            .set(self.ids.text, ui);
-
-        for edit in widget::TextEdit::new(&self.input_name_text)
-            .center_justify()
-            .down_from(self.ids.text, 20.)
-            .set(self.ids.input_name, ui)
-            {
-                self.input_name_text = edit;
-            }
+            if !connecting {
+                for _press in widget::Button::new()
+                    .label("host")
+                    .right_from(self.ids.button, 10.0)
+                    .set(self.ids.host_button, ui)
+                    {
+                        clicked_host = true;
+                    }
 
-        for edit in widget::TextEdit::new(&self.input_host_text)
-            .center_justify()
-            .w(255.)
-            .mid_bottom()
-            .y_place(conrod::position::Place::Start(Some(100.)))
-            .set(self.ids.input_host, ui)
-            {
-                self.input_host_text = edit;
+                for _press in widget::Button::new()
+                    .label("watch")
+                    .left_from(self.ids.button, 10.0)
+                    .set(self.ids.watch_button, ui)
+                    {
+                        self.connector = Some(Connector::start(self.input_host_text.clone(), &self.net_config));
+                        self.spectate = true;
+                        self.status_text = Some(format!("Connecting to {} to watch...", self.input_host_text));
+                    }
             }
 
-        for _press in widget::Button::new()
-            .align_middle_x()
-            .label("start")
-            .down_from(self.ids.input_host, 10.0)
-            .set(self.ids.button, ui)
-            {
-                let player_config = PlayerConfig {
-                    name: self.input_name_text.clone(),
-                    color: self.color.to_fsa()
+            widget::Text::new(if servers.is_empty() { "no LAN servers found" } else { "LAN servers" })
+                .top_left_with_margins_on(ui.window, 20., 20.)
+                .color(conrod::color::WHITE)
+                .font_size(14)
+                .set(self.ids.lan_title, ui);
+
+            let mut prev = self.ids.lan_title;
+
+            for (i, &(addr, ref info)) in servers.iter().enumerate() {
+                let label = if info.is_compatible() {
+                    format!("{} ({} players)", info.name, info.players)
+                } else {
+                    format!("{} (version {})", info.name, info.version)
                 };
 
-                self.switcher.set_next(Some(Box::new(Play::new(
-                    Some(self.input_host_text.clone()),
-                    player_config
-                ))));
+                for _press in widget::Button::new()
+                    .label(&label)
+                    .label_font_size(12)
+                    .w_h(220., 26.)
+                    .down_from(prev, 6.)
+                    .set(self.ids.lan_servers[i], ui)
+                    {
+                        self.input_host_text = addr.to_string();
+                    }
+
+                prev = self.ids.lan_servers[i];
             }
 
+            if let Some(ref status) = self.status_text {
+                widget::Text::new(status)
+                    .center_justify()
+                    .align_middle_x()
+                    .down_from(self.ids.button, 10.0)
+                    .color(if connecting { conrod::color::WHITE } else { conrod::color::LIGHT_RED })
+                    .font_size(14)
+                    .set(self.ids.status, ui);
+            }
+        }
+
+        if clicked_host {
+            self.host();
+        }
+
         Ok(())
     }
 
-    fn switcher(&mut self) -> &mut Switcher {
+    fn switcher(&mut self) -> &mut dyn Switcher {
         &mut self.switcher
     }
 
     fn draw(&mut self, ctx: &mut Context, graphics: &mut G2d, asset_manager: &mut AssetManager) -> GameResult<()> {
-        // A function used for caching glyphs to the texture cache.
-        fn cache_queued_glyphs(graphics: &mut G2d, cache: &mut G2dTexture, rect: conrod::text::rt::Rect<u32>, data: &[u8]) {
-            let mut text_vertex_data = Vec::new();
-            let offset = [rect.min.x, rect.min.y];
-            let size = [rect.width(), rect.height()];
-            let format = texture::Format::Rgba8;
-            let encoder = &mut graphics.encoder;
-            text_vertex_data.clear();
-            text_vertex_data.extend(data.iter().flat_map(|&b| vec![255, 255, 255, b]));
-
-            texture::UpdateTexture::update(cache, encoder, format, &text_vertex_data[..], offset, size)
-                .expect("failed to update texture")
-        }
-
         if let Some(primitives) = self.ui.draw_if_changed() {
-            // Specify how to get the drawable texture from the image. In this case, the image
-            // *is* the texture.
-            fn texture_from_image<T>(img: &T) -> &T { img }
-
-            // Draw the conrod `render::Primitives`.
-            conrod::backend::piston::draw::primitives(
-                primitives,
-                ctx.clone(),
-                graphics,
-                asset_manager.get_texture_mut("ui_cache").unwrap(),
-                &mut self.glyph_cache,
-                &self.image_map,
-                cache_queued_glyphs,
-                texture_from_image
-            );
+            gui::draw_primitives(primitives, *ctx, graphics, asset_manager, &mut self.glyph_cache, &self.image_map);
         }
 
         Ok(())
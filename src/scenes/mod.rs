@@ -1,4 +1,6 @@
 pub mod play;
 pub mod menu;
+pub mod lobby;
 pub mod common;
+pub mod gui;
 pub mod scene;
\ No newline at end of file
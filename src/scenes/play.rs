@@ -1,26 +1,43 @@
-use std::net::TcpStream;
-use std::collections::HashMap;
-use connection::{Connection, NetToken, EventType};
+use std::collections::{HashMap, VecDeque};
+use connection::{ConnectEvent, Connection, Connector, NetConfig, NetToken};
+use protocol::{Message, ProtocolError, MAX_CHAT_LEN};
+use server::ServerHandle;
+use interpolation::{Snapshot, SnapshotBuffer};
+use snapshot::{SnapshotDelta, SnapshotHistory};
+use world::{self, Wall, PLAYER_SPEED, SPAWN_POS};
 use piston_window::types::Color;
 use piston_window::*;
 use scenes::common::*;
-use scenes::scene::{Scene, SceneInstance, BaseSwitcher, Switcher};
+use scenes::scene::{Scene, BaseSwitcher, Switcher};
 use scenes::menu::Menu;
+use scenes::gui;
 use std::f64;
 use vecmath::*;
 use piston_window::math::*;
 use piston_window::types::Rectangle as Rect;
 use cgmath;
 use collision as cgcoll;
-use collision::{Aabb, ContinuousTransformed, DiscreteTransformed, Contains, HasAabb};
+use collision::{Aabb, ContinuousTransformed, Contains, HasAabb};
 use cgmath::MetricSpace;
-use cgmath::Rotation;
 use std::cmp::Ordering;
 use button_tracker::ButtonController;
 use asset_manager::AssetManager;
 
 const W_HEIGHT: f64 = 1000.0;
 const W_WIDTH: f64 = 1000.0;
+// unacknowledged inputs kept for re-simulation, older ones are given up on
+const MAX_PENDING_INPUTS: usize = 120;
+// mispredictions smaller than this are not worth correcting
+const RECONCILE_EPSILON: f64 = 0.01;
+// older chat lines scroll out of the log
+const CHAT_LOG_LINES: usize = 8;
+const CHAT_FONT_SIZE: u32 = 14;
+// terminus is monospaced, so text width is known without measuring it
+const CHAT_CHAR_WIDTH: f64 = 7.;
+// seconds for a correction to fade out by ~63%
+const CORRECTION_SMOOTHING: f64 = 0.1;
+// chat lines from the server operator
+const NOTICE_COLOR: Color = [1., 1., 0., 1.];
 
 trait Camera {
     fn world_to_screen(&self, world: Vec2d) -> Vec2d;
@@ -37,16 +54,6 @@ impl Camera for GameObject {
     }
 }
 
-trait Position {
-    fn x_y(&self) -> (f64, f64);
-}
-
-impl Position for Vec2d {
-    fn x_y(&self) -> (f64, f64) {
-        (self[0], self[1])
-    }
-}
-
 #[derive(Clone)]
 struct GameObject {
     pos: Vec2d,
@@ -68,7 +75,7 @@ impl GameObject {
         GameObject {
             rotation: 0.,
             pos: Vec2d::from([x, y]),
-            color: color,
+            color,
             velocity: Vec2d::from([0., 0.]),
             bounding_box,
             collides
@@ -80,7 +87,7 @@ impl GameObject {
     }
 
     fn get_pos(&self) -> Vec2d {
-        self.pos.clone()
+        self.pos
     }
 
     fn update_position(&mut self, dt: f64, colliders: Option<&Vec<GameObject>>) {
@@ -89,7 +96,7 @@ impl GameObject {
             let friction = 0.8;
             let new_pos = transform_pos(transform, self.pos);
 
-            if let Some(ref colliders) = colliders {
+            if let Some(colliders) = colliders {
                 if colliders.iter().any(|collider| GameObject::collides_with_at_pos(&new_pos, collider)) {
                     self.velocity = Vec2d::from([0., 0.]);
                 } else {
@@ -108,7 +115,7 @@ impl GameObject {
     fn collides_with_at_pos(pos: &Vec2d, other: &GameObject) -> bool {
         if let Some(collider_rect) = other.get_bound() {
             let bound_box = collider_rect.get_bound();
-            let point: cgmath::Point2<f64> = sub(pos.clone(), other.get_pos()).into();
+            let point: cgmath::Point2<f64> = sub(*pos, other.get_pos()).into();
 
             other.collides && bound_box.contains(&point)
         } else {
@@ -131,11 +138,40 @@ impl GameObject {
 
         Some(self.get_pos())
     }
+
+    fn wall(wall: &Wall) -> GameObject {
+        GameObject::new(wall.pos[0], wall.pos[1], wall.color, Some((wall.half_size[0], wall.half_size[1])), true)
+    }
 }
 
 struct Player {
     name: String,
-    obj_index: usize
+    obj_index: usize,
+    // received states, used to move remote players smoothly
+    snapshots: SnapshotBuffer
+}
+
+// local input applied right away and kept until the server acknowledges it
+struct PendingInput {
+    seq: u32,
+    dt: f64,
+    direction: Option<Vec2d>,
+    // predicted state right after the input was applied
+    pos: Vec2d,
+    velocity: Vec2d
+}
+
+struct ChatLine {
+    name: String,
+    color: Color,
+    text: String
+}
+
+// lost connection being resumed in the background, the player keeps playing meanwhile
+struct Reconnect {
+    connector: Connector,
+    addr: String,
+    attempt: u32
 }
 
 #[derive(Clone)]
@@ -145,32 +181,60 @@ pub struct PlayerConfig {
 }
 
 // if connection is not established player will be at   players[0]
-// else controllable player will be at                  players[connection.token]
+// else controllable player will be at                  players[token]
 pub struct Play {
     switcher: BaseSwitcher,
-    free_area: Rect,
     camera: GameObject,
     objects: Vec<GameObject>,
     players: HashMap<NetToken, Player>,
     connection: Option<Connection>,
+    // kept while reconnecting, so the player does not disappear with the connection
+    token: NetToken,
+    reconnect: Option<Reconnect>,
+    // the game this client hosts, it ends with the scene
+    hosted: Option<ServerHandle>,
+    // name of the level the walls are from, see `world::map`
+    map: String,
+    // watches the others without a player of its own
+    spectator: bool,
+    // the player the camera of a spectator follows, the cursor moves it when there is none
+    watched: Option<NetToken>,
     player_config: PlayerConfig,
     cursor: [f64; 2],
     button_tracker: ButtonController,
-    prev_not: Option<Vec2d>
+    net_config: NetConfig,
+    // time since the last sent player state and what was sent
+    send_timer: f64,
+    // seconds since the scene started
+    clock: f64,
+    last_sent: Option<(Vec2d, f64, Vec2d)>,
+    input_seq: u32,
+    pending_inputs: VecDeque<PendingInput>,
+    // world snapshots received from the server, bases for the next deltas
+    world: SnapshotHistory,
+    // difference between shown and simulated local player position, fades out over time
+    correction: Vec2d,
+    chat_log: VecDeque<ChatLine>,
+    // text being typed while the chat box is open
+    chat_input: Option<String>
 }
 
+// colors and aux are for the commented out debug drawing of the rays
+#[allow(dead_code)]
 struct Angle {
     rad: f64,
     color: Color,
     aux: bool
 }
 
+#[allow(dead_code)]
 impl Angle {
     fn is_aux(&self) -> bool {
         self.aux
     }
 }
 
+#[allow(dead_code)]
 struct Intersection {
     angle: f64,
     color: Color,
@@ -178,39 +242,61 @@ struct Intersection {
 }
 
 impl Play {
-    pub fn new(auto_connect: Option<String>, player_config: PlayerConfig) -> Play {
-        let objects = vec![
-            GameObject::new(400.0, 300.0, WHITE, Some((W_WIDTH / 2., W_HEIGHT / 2.)), false),
-            GameObject::new(200.0, 300.0, WHITE, Some((100., 10.)), true),
-            GameObject::new(500.0, 100.0, RED, Some((10., 100.)), true),
-            GameObject::new(50.0, 40.0, GREEN, Some((100., 100.)), true),
-            GameObject::new(600.0, 600.0, BLUE, Some((100., 150.)), true),
-            GameObject::new(50.0, 500.0, BLUE, Some((50., 50.)), true),
-            GameObject::new(50.0, 650.0, WHITE, Some((50., 50.)), true),
-            GameObject::new(200.0, 500.0, RED, Some((50., 50.)), true),
-            GameObject::new(200.0, 650.0, GREEN, Some((50., 50.)), true)
+    pub fn new(connection: Option<Connection>, player_config: PlayerConfig, net_config: NetConfig, hosted: Option<ServerHandle>) -> Play {
+        let mut objects = vec![
+            GameObject::new(400.0, 300.0, WHITE, Some((W_WIDTH / 2., W_HEIGHT / 2.)), false)
         ];
 
-        let mut play = Play {
+        // the server checks movement against the same walls, it tells if they are another map's
+        for wall in world::walls() {
+            objects.push(GameObject::wall(&wall));
+        }
+
+        let token = match connection {
+            Some(ref connection) => {
+                println!("connection established, net_token= {}", connection.token);
+                connection.token
+            },
+            None => 0 as NetToken
+        };
+
+        Play {
             switcher: BaseSwitcher::new(None),
-            objects: objects,
+            objects,
             camera: GameObject::new(0., 0., BLUE, None, false),
             players: HashMap::new(),
-            free_area: Rect::from([200., 150., 600., 450.]),
-            connection: None,
-            player_config: player_config,
+            connection,
+            token,
+            reconnect: None,
+            hosted,
+            map: world::DEFAULT_MAP.to_string(),
+            spectator: false,
+            watched: None,
+            player_config,
             cursor: [0f64; 2],
             button_tracker: ButtonController::new(),
-            prev_not: None
-        };
+            net_config,
+            send_timer: 0.,
+            clock: 0.,
+            last_sent: None,
+            input_seq: 0,
+            pending_inputs: VecDeque::new(),
+            world: SnapshotHistory::new(),
+            correction: [0., 0.],
+            chat_log: VecDeque::new(),
+            chat_input: None
+        }
+    }
 
-        if let Some(addr) = auto_connect {
-            match play.connect(addr) {
-                Err(err) => println!("Failed to connect: {}", err),
-                _ => ()
-            }
+    // watching the game, the server will not let this client spawn
+    pub fn spectate(mut connection: Connection, player_config: PlayerConfig, net_config: NetConfig) -> Play {
+        if let Err(err) = connection.send(&Message::Spectate) {
+            println!("Failed to start spectating: {}", err);
         }
 
+        let mut play = Play::new(Some(connection), player_config, net_config, None);
+        play.spectator = true;
+
         play
     }
 
@@ -222,76 +308,519 @@ impl Play {
         }
     }
 
-    pub fn connect(&mut self, host: String) -> Result<(), String> {
-        match TcpStream::connect(host) {
-            Ok(stream) => match Connection::new(stream) {
-                Ok(connection) => {
-                    println!("connection established, net_token= {}", connection.token);
-                    self.connection = Some(connection);
-
-                    Ok(())
-                }
-                Err(err) => Err(err)
-            },
-            Err(e) => Err(format!("{:?}", e.kind()))
-        }
-    }
-
     fn spawn_player(&mut self, token: NetToken, pos: Vec2d, name: String, color: Color) {
         let idx = self.objects.len();
         self.objects.push(GameObject::new(pos[0], pos[1], color, None, false));
 
+        let mut snapshots = SnapshotBuffer::new();
+        snapshots.push(Snapshot { time: self.clock, pos, rotation: 0., velocity: [0., 0.] });
+
         self.players.insert(token, Player {
-            name: name,
-            obj_index: idx
+            name,
+            obj_index: idx,
+            snapshots
         });
     }
 
+    fn despawn_player(&mut self, token: NetToken) {
+        if self.watched == Some(token) {
+            self.watched = None;
+        }
+
+        if let Some(player) = self.players.remove(&token) {
+            self.objects.remove(player.obj_index);
+
+            // objects after the removed one moved one slot back
+            for other in self.players.values_mut() {
+                if other.obj_index > player.obj_index {
+                    other.obj_index -= 1;
+                }
+            }
+        }
+    }
+
     fn spawn_self_player(&mut self, pos: Vec2d) {
-        let token = match self.connection {
-            Some(Connection { ref token, .. }) => token.clone(),
-            None => 0 as NetToken
+        let token = self.token();
+
+        let PlayerConfig { name, color } = self.player_config.clone();
+
+        self.spawn_player(token, pos, name.clone(), color);
+
+        if let Some(ref mut connection) = self.connection {
+            if let Err(err) = connection.send_spawn_event(name, pos, color) {
+                println!("Failed to send spawn event: {}", err);
+            }
+        }
+    }
+
+    fn update_player_pos(&mut self, token: NetToken, new_pos: Vec2d, rotation: f64, velocity: Vec2d) {
+        let time = self.clock;
+
+        if let Some(player) = self.players.get_mut(&token) {
+            player.snapshots.push(Snapshot { time, pos: new_pos, rotation, velocity });
+        }
+    }
+
+    // remote players are shown `interpolation_delay` in the past, between two received states
+    fn interpolate_remote_players(&mut self) {
+        let token = self.token();
+        let render_time = self.clock - self.net_config.interpolation_delay;
+
+        for (player_token, player) in self.players.iter_mut() {
+            if *player_token == token {
+                continue;
+            }
+
+            if let (Some((pos, rotation)), Some(obj)) = (player.snapshots.sample(render_time), self.objects.get_mut(player.obj_index)) {
+                obj.pos = pos;
+                obj.rotation = rotation;
+            }
+        }
+    }
+
+    fn handle_events(&mut self) {
+        let results = match self.connection {
+            Some(ref mut connection) => connection.poll_messages(),
+            None => vec![]
         };
 
+        for result in results {
+            match result {
+                Ok(message) => self.handle_message(message),
+                // one bad packet is not a reason to drop the whole session
+                Err(ref err) if !err.is_fatal() => println!("Dropped bad packet: {}", err),
+                Err(err) => self.disconnect(err)
+            }
+        }
+    }
+
+    pub fn handle_message(&mut self, message: Message) {
+        match message {
+            // our own player is spawned locally, the server only echoes it back
+            Message::Spawn { token, name, pos, color } => if !self.players.contains_key(&token) {
+                self.spawn_player(token, pos, name, color);
+            },
+            Message::UpdatePos { token, pos, rotation, velocity, .. } => if token != self.token() {
+                self.update_player_pos(token, pos, rotation, velocity);
+            },
+            Message::Despawn { token } => if token != self.token() {
+                self.despawn_player(token);
+            },
+            Message::Ack { seq, pos } => self.reconcile(seq, pos),
+            Message::Snapshot(delta) => self.apply_snapshot(&delta),
+            Message::Chat { token, text } => if token != self.token() {
+                self.receive_chat(token, text);
+            },
+            Message::Notice { text } => self.receive_notice(text),
+            Message::Disconnect { reason } => self.kicked(reason),
+            Message::Map { name } => self.load_map(name),
+            // only the server receives these, pongs and sessions are taken by the connection
+            Message::SnapshotAck { .. } | Message::Ping { .. } | Message::Pong { .. }
+                | Message::Session { .. } | Message::Resume { .. } | Message::Resumed { .. }
+                | Message::Spectate => (),
+            // the match is already running
+            Message::Join { .. } | Message::Ready { .. } | Message::Start | Message::Lobby { .. } => ()
+        }
+    }
+
+    fn apply_snapshot(&mut self, delta: &SnapshotDelta) {
+        let world = match self.world.apply(delta) {
+            Ok(Some(world)) => world.clone(),
+            // older than the one we already have
+            Ok(None) => return,
+            // made against a snapshot we no longer have, a later delta or a full snapshot will do
+            Err(ProtocolError::UnknownBase(_)) => return,
+            Err(err) => {
+                println!("Dropped bad snapshot: {}", err);
+                return;
+            }
+        };
+
+        // the next deltas are made against this snapshot
+        if let Some(ref mut connection) = self.connection {
+            if let Err(err) = connection.send(&Message::SnapshotAck { tick: world.tick }) {
+                println!("Failed to acknowledge snapshot: {}", err);
+            }
+        }
+
+        let token = self.token();
+
+        // players not spawned yet are skipped, their spawn event has the name and color
+        for (player_token, state) in world.entities.iter() {
+            if *player_token != token {
+                let (pos, rotation, velocity) = state.dequantize();
+                self.update_player_pos(*player_token, pos, rotation, velocity);
+            }
+        }
+    }
+
+    fn record_input(&mut self, dt: f64, direction: Option<Vec2d>) {
+        // offline there is nobody to disagree with the prediction
+        if self.connection.is_none() {
+            return;
+        }
+
+        let (pos, velocity) = match self.player() {
+            Some(obj) => (obj.get_pos(), obj.velocity),
+            None => return
+        };
+
+        self.input_seq = self.input_seq.wrapping_add(1);
+
+        if self.pending_inputs.len() == MAX_PENDING_INPUTS {
+            self.pending_inputs.pop_front();
+        }
+
+        self.pending_inputs.push_back(PendingInput { seq: self.input_seq, dt, direction, pos, velocity });
+    }
+
+    // the server confirmed where the player is after input `seq`: if the prediction was wrong
+    // the player is moved there and inputs the server has not seen yet are applied again
+    fn reconcile(&mut self, seq: u32, server_pos: Vec2d) {
+        let confirmed = match self.pending_inputs.iter().position(|input| input.seq == seq) {
+            Some(idx) => self.pending_inputs.drain(..idx + 1).next_back().unwrap(),
+            // too old to be remembered, nothing to compare with
+            None => return
+        };
+
+        if vec2_len(sub(confirmed.pos, server_pos)) < RECONCILE_EPSILON {
+            return;
+        }
+
+        let colliders = self.colliders();
+        let mut obj = match self.player() {
+            Some(obj) => obj.clone(),
+            None => return
+        };
+        let predicted_pos = obj.get_pos();
+
+        obj.pos = server_pos;
+        obj.velocity = confirmed.velocity;
+
+        for input in self.pending_inputs.iter_mut() {
+            obj.update_position(input.dt, Some(&colliders));
+
+            if let Some(direction) = input.direction {
+                obj.move_to(direction, PLAYER_SPEED);
+            }
+
+            input.pos = obj.get_pos();
+            input.velocity = obj.velocity;
+        }
+
+        // the player is still shown where it was and slides to the corrected position
+        self.correction = add(self.correction, sub(predicted_pos, obj.get_pos()));
+
+        if let Some(player) = self.player_mut() {
+            player.pos = obj.pos;
+            player.velocity = obj.velocity;
+        }
+    }
+
+    fn receive_chat(&mut self, token: NetToken, text: String) {
+        // the server relays chat of spawned players only, so the name and color are known
+        let line = match self.players.get(&token) {
+            Some(player) => ChatLine { name: player.name.clone(), color: self.objects[player.obj_index].color, text },
+            None => return
+        };
+
+        self.push_chat_line(line);
+    }
+
+    fn receive_notice(&mut self, text: String) {
+        self.push_chat_line(ChatLine { name: "server".to_string(), color: NOTICE_COLOR, text });
+    }
+
+    fn say(&mut self, text: String) {
+        let text = text.trim().to_string();
+
+        // nobody would know who said it
+        if text.is_empty() || self.player().is_none() {
+            return;
+        }
+
+        if let Some(ref mut connection) = self.connection {
+            if let Err(err) = connection.send_chat(text.clone()) {
+                println!("Failed to send chat message: {}", err);
+                return;
+            }
+        }
+
         let PlayerConfig { name, color } = self.player_config.clone();
+        self.push_chat_line(ChatLine { name, color, text });
+    }
 
-        self.spawn_player(token, pos.clone(), name.clone(), color.clone());
+    fn push_chat_line(&mut self, line: ChatLine) {
+        if self.chat_log.len() == CHAT_LOG_LINES {
+            self.chat_log.pop_front();
+        }
 
-        self.connection.as_mut()
-            .and_then(|ref mut connection| Some(connection.send_spawn_event(name, pos, color)));
+        self.chat_log.push_back(line);
     }
 
-    fn update_player_pos(&mut self, token: NetToken, new_pos: Vec2d) {
-        match self.players.get_mut(&token) {
-            Some(&mut Player { obj_index: ref idx, .. }) => self.objects.get_mut(idx.clone()),
+    // keys typed while the chat box is open, enter sends the text and closes it
+    fn chat_key_press(&mut self, button: Button) {
+        match button {
+            Button::Keyboard(Key::Return) => {
+                if let Some(text) = self.chat_input.take() {
+                    self.say(text);
+                }
+            },
+            Button::Keyboard(Key::Backspace) => {
+                if let Some(ref mut input) = self.chat_input {
+                    input.pop();
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn colliders(&self) -> Vec<GameObject> {
+        let mut colliders = self.objects.to_vec();
+        colliders.retain(|obj| obj.collides && obj.get_bound().is_some());
+
+        colliders
+    }
+
+    // the server keeps the player for a while, so a lost connection is resumed in the background,
+    // without a session co-op is over and it's back to the menu
+    fn disconnect(&mut self, reason: ProtocolError) {
+        println!("Disconnected from server: {}", reason);
+
+        if let Some(connection) = self.connection.take() {
+            let addr = connection.addr.to_string();
+
+            match connection.session() {
+                Some(session) => {
+                    println!("Reconnecting to {}", addr);
+
+                    let connector = Connector::resume(addr.clone(), &self.net_config, session);
+                    self.reconnect = Some(Reconnect { connector, addr, attempt: 0 });
+                },
+                None => self.back_to_menu(addr, format!("Disconnected from server: {}", reason))
+            }
+        }
+    }
+
+    // the server closed the connection on purpose, there is nothing to resume
+    fn kicked(&mut self, reason: String) {
+        println!("Disconnected by server: {}", reason);
+
+        if let Some(connection) = self.connection.take() {
+            self.back_to_menu(connection.addr.to_string(), format!("Disconnected by server: {}", reason));
+        }
+    }
+
+    // every player is gone with the old level, the server spawns nobody on the new one
+    fn load_map(&mut self, name: String) {
+        // told on every connect, also when nothing changed
+        if name == self.map {
+            return;
+        }
+
+        let walls = match world::map(&name) {
+            Some(walls) => walls,
+            None => {
+                println!("Unknown map {}, keeping {}", name, self.map);
+                return;
+            }
+        };
+
+        self.players.clear();
+        self.watched = None;
+        self.pending_inputs.clear();
+        self.last_sent = None;
+        self.correction = [0., 0.];
+
+        // the floor stays
+        self.objects.truncate(1);
+        self.objects.extend(walls.iter().map(GameObject::wall));
+
+        self.receive_notice(format!("map changed to {}", name));
+        self.map = name;
+    }
+
+    fn poll_reconnect(&mut self) {
+        let event = match self.reconnect {
+            Some(ref mut reconnect) => reconnect.connector.poll(),
             None => None
-        }.and_then(|obj| {
-            obj.pos = new_pos;
+        };
 
-            Some(())
-        });
+        match event {
+            Some(ConnectEvent::Attempt(attempt)) => if let Some(ref mut reconnect) = self.reconnect {
+                reconnect.attempt = attempt;
+            },
+            Some(ConnectEvent::Connected(connection)) => {
+                self.reconnect = None;
+                self.resume(*connection);
+            },
+            Some(ConnectEvent::Failed(err)) => if let Some(reconnect) = self.reconnect.take() {
+                println!("Failed to reconnect: {}", err);
+                self.back_to_menu(reconnect.addr, format!("Failed to reconnect: {}", err));
+            },
+            None => ()
+        }
     }
 
-    fn player_mut(&mut self) -> Option<&mut GameObject> {
-        let token = match self.connection {
-            Some(Connection { ref token, .. }) => token.clone(),
-            None => 0 as NetToken
+    fn resume(&mut self, connection: Connection) {
+        println!("connection resumed, net_token= {}", connection.token);
+
+        // the server spawns the others again with their current state
+        let remote_tokens: Vec<NetToken> = self.players.keys()
+            .filter(|token| **token != self.token)
+            .cloned()
+            .collect();
+
+        // the watched player is spawned again too
+        let watched = self.watched;
+
+        for token in remote_tokens {
+            self.despawn_player(token);
+        }
+
+        self.watched = watched;
+
+        // the session was gone, the player joins as a new one
+        if connection.token != self.token {
+            if let Some(player) = self.players.remove(&self.token) {
+                self.players.insert(connection.token, player);
+            }
+        }
+
+        self.token = connection.token;
+        self.connection = Some(connection);
+        self.world = SnapshotHistory::new();
+        self.pending_inputs.clear();
+        self.last_sent = None;
+
+        // spectators are not told apart from players by the session
+        if self.spectator {
+            if let Some(ref mut connection) = self.connection {
+                if let Err(err) = connection.send(&Message::Spectate) {
+                    println!("Failed to start spectating: {}", err);
+                }
+            }
+
+            return;
+        }
+
+        // a resumed player is just moved, a new one is spawned for the others
+        let pos = match self.player() {
+            Some(obj) => obj.get_pos(),
+            None => return
+        };
+        let PlayerConfig { name, color } = self.player_config.clone();
+
+        if let Some(ref mut connection) = self.connection {
+            if let Err(err) = connection.send_spawn_event(name, pos, color) {
+                println!("Failed to send spawn event: {}", err);
+            }
+        }
+    }
+
+    fn back_to_menu(&mut self, addr: String, status: String) {
+        let menu = Menu::with_status(self.player_config.clone(), addr, status);
+
+        self.switcher.set_next(Some(Box::new(menu)));
+    }
+
+    fn send_player_state(&mut self) {
+        let state = match self.player() {
+            Some(obj) => (obj.get_pos(), obj.rotation, obj.velocity),
+            None => return
+        };
+
+        // nothing to tell if the player is standing still
+        if self.last_sent == Some(state) {
+            return;
+        }
+
+        if let Some(ref mut connection) = self.connection {
+            if let Err(err) = connection.send_update_pos_event(self.input_seq, state.0, state.1, state.2) {
+                println!("Failed to send player state: {}", err);
+                return;
+            }
+        }
+
+        self.send_timer = 0.;
+        self.last_sent = Some(state);
+    }
+
+    fn connection_status(&self) -> (String, Color) {
+        if let Some(ref reconnect) = self.reconnect {
+            let status = format!("reconnecting to {} (attempt {})...", reconnect.addr, reconnect.attempt);
+
+            return (status, RED);
+        }
+
+        let connection = match self.connection {
+            Some(ref connection) => connection,
+            None => return ("offline".to_string(), WHITE)
+        };
+
+        let silence = connection.silence();
+
+        // pongs are overdue, the server may be gone
+        if silence > self.net_config.ping_interval * 2 {
+            let status = format!("{}: no response for {} s", connection.addr, silence.as_secs());
+
+            return (status, RED);
+        }
+
+        let ping = match connection.rtt() {
+            Some(rtt) => format!("{} ms", rtt.as_millis()),
+            None => "-".to_string()
+        };
+        let addr = match self.hosted {
+            Some(ref hosted) => format!("hosting on port {}", hosted.addr().port()),
+            None => connection.addr.to_string()
         };
+        let loss = match connection.packet_loss() {
+            Some(loss) => format!("loss {:.0}%", loss * 100.),
+            // tcp loses nothing, it is only late
+            None => "over tcp".to_string()
+        };
+        let status = format!("{}: ping {}, {}", addr, ping, loss);
+
+        (status, WHITE)
+    }
+
+    fn token(&self) -> NetToken {
+        self.token
+    }
+
+    fn player_mut(&mut self) -> Option<&mut GameObject> {
+        let token = self.token();
 
         match self.players.get_mut(&token) {
-            Some(&mut Player { obj_index: ref idx, .. }) => self.objects.get_mut(idx.clone()),
+            Some(&mut Player { obj_index: ref idx, .. }) => self.objects.get_mut(*idx),
             None => None
         }
     }
 
-    fn player(&self) -> Option<&GameObject> {
-        let token = match self.connection {
-            Some(Connection { ref token, .. }) => token.clone(),
-            None => 0 as NetToken
+    // the camera of a spectator goes from one player to the next in the order they joined,
+    // after the last one it follows the cursor again
+    fn watch_next(&mut self) {
+        let mut tokens: Vec<NetToken> = self.players.keys().cloned().collect();
+        tokens.sort();
+
+        self.watched = match self.watched {
+            Some(watched) => tokens.into_iter().find(|token| *token > watched),
+            None => tokens.first().cloned()
         };
+    }
+
+    fn watched(&self) -> Option<&GameObject> {
+        self.watched
+            .and_then(|token| self.players.get(&token))
+            .and_then(|player| self.objects.get(player.obj_index))
+    }
+
+    fn player(&self) -> Option<&GameObject> {
+        let token = self.token();
 
         match self.players.get(&token) {
-            Some(&Player { obj_index: ref idx, .. }) => self.objects.get(idx.clone()),
+            Some(Player { obj_index: idx, .. }) => self.objects.get(*idx),
             None => None
         }
     }
@@ -306,17 +835,21 @@ fn transform(dx: f64, dy: f64, rot: f64) -> cgmath::Decomposed<cgmath::Vector2<f
 }
 
 impl Scene for Play {
-    fn switcher(&mut self) -> &mut Switcher {
+    fn switcher(&mut self) -> &mut dyn Switcher {
         &mut self.switcher
     }
 
     fn update(&mut self, dt: f64) -> GameResult<()> {
+        self.clock += dt;
         self.button_tracker.update();
 
+        self.handle_events();
+        self.poll_reconnect();
+        self.interpolate_remote_players();
+
         // update objects positions according to collision with others
         {
-            let mut colliders = self.objects.to_vec();
-            colliders.retain(|obj| obj.collides && obj.get_bound().is_some());
+            let colliders = self.colliders();
 
             for obj in self.objects.iter_mut() {
                 obj.update_position(dt, Some(&colliders));
@@ -335,19 +868,37 @@ impl Scene for Play {
                     Key::Right
                 ];
 
+                let mut direction = None;
+
                 for key in movement_keys.iter() {
-                    let key_pressed = self.button_tracker.current_pressed(&Button::Keyboard(key.clone()));
+                    let key_pressed = self.button_tracker.current_pressed(&Button::Keyboard(*key));
 
                     if key_pressed {
-                        self.player_mut().unwrap().move_to(Vec2d::from(Direction::from(key.clone())), 200.);
+                        direction = Some(Vec2d::from(Direction::from(*key)));
+                        self.player_mut().unwrap().move_to(direction.unwrap(), PLAYER_SPEED);
                     }
                 }
+
+                // remembered to be applied again if the server disagrees with the prediction
+                self.record_input(dt, direction);
+            }
+        }
+
+        self.correction = mul_scalar(self.correction, (-dt / CORRECTION_SMOOTHING).exp());
+
+        // broadcast local player state not more often than send_rate allows,
+        // only after the input it includes was recorded
+        {
+            self.send_timer += dt;
+
+            if self.send_timer >= 1. / self.net_config.send_rate {
+                self.send_player_state();
             }
         }
 
         // update camera pos
         {
-            let screen_vec = if let Some(obj) = self.player() {
+            let screen_vec = if let Some(obj) = self.player().or_else(|| self.watched()) {
                 self.camera.world_to_screen(obj.get_pos())
             } else {
                 self.cursor
@@ -402,7 +953,7 @@ impl Scene for Play {
                 if let Some(b_box) = obj.get_bound() {
                     let screen_pos = self.camera.world_to_screen(obj.get_pos());
                     let pos = multiply(ctx.transform, translate(screen_pos)).rot_rad(obj.rotation);
-                    let obj_border = Rectangle::new_border(obj.color.clone(), 0.5);
+                    let obj_border = Rectangle::new_border(obj.color, 0.5);
 
                     let b_box = b_box.get_bound();
                     let (min, max) = (b_box.min(), b_box.max());
@@ -419,21 +970,17 @@ impl Scene for Play {
 
         // Shadow-casting
         {
-            let source = if let Some(obj) = self.player() {
+            // a spectator sees what the watched player sees
+            let source = if let Some(obj) = self.player().or_else(|| self.watched()) {
                 self.camera.world_to_screen(obj.get_pos())
             } else {
                 self.cursor
             };
-            let rotation = if let Some(obj) = self.player() {
-                obj.rotation.clone()
-            } else {
-                0.
-            };
 
             fn add_auxiliary_angles(current: &cgmath::Point2<f64>, prev: &cgmath::Point2<f64>, next: &cgmath::Point2<f64>, angles: &mut Vec<Angle>, aux_angle: f64, angle: f64) {
                 let direction = [angle.cos(), angle.sin()];
-                let prev_vec = sub(current.clone().into(), prev.clone().into());
-                let next_vec = sub(next.clone().into(), current.clone().into());
+                let prev_vec = sub((*current).into(), (*prev).into());
+                let next_vec = sub((*next).into(), (*current).into());
                 let projection_axis = perp(direction);
                 let p_1 = vec2_dot(prev_vec, projection_axis);
                 let p_2 = vec2_dot(next_vec, projection_axis);
@@ -470,13 +1017,13 @@ impl Scene for Play {
 
             // get all angles of corners of all objects
             {
-                for (j, obj) in self.objects.iter().enumerate() {
+                for obj in self.objects.iter() {
                     if let Some(rect_collider) = obj.get_bound() {
                         let screen_pos = self.camera.world_to_screen(obj.get_pos());
                         let corners = rect_collider.get_bound().to_corners();
 
-                        for (i, corner) in corners.iter().enumerate() {
-                            let corner_screen = add(corner.clone().into(), screen_pos);
+                        for corner in corners.iter() {
+                            let corner_screen = add((*corner).into(), screen_pos);
                             let n = vec2_normalized(sub(corner_screen, source));
                             let angle = n[1].atan2(n[0]);
 
@@ -506,8 +1053,8 @@ impl Scene for Play {
                                 let result = rect_collider.intersection_transformed(&ray, &transform)
                                     .or_else(|| {
                                         // Dummy correction for ray intersection
-                                        for (i, corner) in rect_collider.get_bound().to_corners().iter().enumerate() {
-                                            let corner_screen = add(corner.clone().into(), screen_pos);
+                                        for corner in rect_collider.get_bound().to_corners().iter() {
+                                            let corner_screen = add((*corner).into(), screen_pos);
                                             let n = vec2_normalized(sub(corner_screen, source));
                                             let _angle = n[1].atan2(n[0]);
 
@@ -542,7 +1089,7 @@ impl Scene for Play {
                                 let corners = rect_collider.get_bound().to_corners();
 
                                 for (i, corner) in corners.iter().enumerate() {
-                                    let corner_screen = add(corner.clone().into(), screen_pos);
+                                    let corner_screen = add((*corner).into(), screen_pos);
                                     let e = 0.0001;
 
                                     if closest.distance(corner_screen.into()) < e {
@@ -561,10 +1108,10 @@ impl Scene for Play {
                                                 i + 1
                                             };
 
-                                            let ref prev = corners[prev_index];
-                                            let ref next = corners[next_index];
+                                            let prev = &corners[prev_index];
+                                            let next = &corners[next_index];
 
-                                            let corner_screen = add(corner.clone().into(), screen_pos);
+                                            let corner_screen = add((*corner).into(), screen_pos);
                                             let n = vec2_normalized(sub(corner_screen, source));
                                             let angle = n[1].atan2(n[0]);
 
@@ -622,11 +1169,11 @@ impl Scene for Play {
             {
                 let grey = [0.2, 0.2, 0.2, 0.2];
                 let polygon = Polygon::new(grey);
-                for (i, &Intersection { angle: angle, color: color, point: p }) in intersects.iter().enumerate() {
+                for (i, &Intersection { point: p, .. }) in intersects.iter().enumerate() {
                     if intersects.len() > i + 1 {
                         let next = intersects[i + 1].point;
                         let polygon_points = [p.into(), next.into(), source];
-                        polygon.draw_tri(&polygon_points, &ctx.draw_state, ctx.transform.clone(), graphics);
+                        polygon.draw_tri(&polygon_points, &ctx.draw_state, ctx.transform, graphics);
                     }
 
                     //let line = Line::new(color, 0.5);
@@ -639,16 +1186,34 @@ impl Scene for Play {
                     let last = last.point;
 
                     let polygon_points: [Vec2d; 3] = [first.into(), last.into(), source];
-                    polygon.draw_tri(&polygon_points, &ctx.draw_state, ctx.transform.clone(), graphics);
+                    polygon.draw_tri(&polygon_points, &ctx.draw_state, ctx.transform, graphics);
                 }
             }
         }
 
+        // Draw remote players sprites
+        {
+            let token = self.token();
+            let remote_players: Vec<(Vec2d, f64)> = self.players.iter()
+                .filter(|&(player_token, _)| *player_token != token)
+                .filter_map(|(_, player)| self.objects.get(player.obj_index))
+                .map(|obj| (obj.get_pos(), obj.rotation))
+                .collect();
+
+            for (pos, rot) in remote_players {
+                let screen_pos = self.camera.world_to_screen(pos);
+                let player_transform = multiply(ctx.transform, translate(screen_pos));
+
+                let rect = rectangle::centered_square(0.0, 0.0, 50.0);
+                self.render_texture("player_sprite", rect, player_transform.rot_rad(rot), graphics, asset_manager);
+            }
+        }
+
         // Draw player sprite and FOV area
         {
             if self.player().is_some() {
-                let rot = self.player().unwrap().rotation.clone();
-                let pos = self.player().unwrap().get_pos();
+                let rot = self.player().unwrap().rotation;
+                let pos = add(self.player().unwrap().get_pos(), self.correction);
                 let screen_pos = self.camera.world_to_screen(pos);
                 let player_transform = multiply(ctx.transform, translate(screen_pos));
 
@@ -662,21 +1227,92 @@ impl Scene for Play {
             }
         }
 
+        // connection quality HUD
+        {
+            let (status, color) = self.connection_status();
+
+            if let Some(glyphs) = asset_manager.get_glyphs_mut() {
+                let transform = ctx.transform.trans(10., 20.);
+                Text::new_color(color, 14).draw(&status, glyphs, &ctx.draw_state, transform, graphics);
+            }
+        }
+
+        if self.spectator {
+            let status = match self.watched.and_then(|token| self.players.get(&token)) {
+                Some(player) => format!("spectating {} (tab: next player, no chat)", player.name),
+                None => "spectating (tab: follow a player, no chat)".to_string()
+            };
+
+            if let Some(glyphs) = asset_manager.get_glyphs_mut() {
+                let transform = ctx.transform.trans(10., 40.);
+                Text::new_color(WHITE, 14).draw(&status, glyphs, &ctx.draw_state, transform, graphics);
+            }
+        }
+
+        // chat log over the chat box, newest line at the bottom
+        if let Some(glyphs) = asset_manager.get_glyphs_mut() {
+            let bottom = gui::UI_HEIGHT - 50.;
+
+            for (i, line) in self.chat_log.iter().rev().enumerate() {
+                let name = format!("{}: ", line.name);
+                let name_width = name.chars().count() as f64 * CHAT_CHAR_WIDTH;
+                let transform = ctx.transform.trans(10., bottom - i as f64 * 18.);
+
+                Text::new_color(line.color, CHAT_FONT_SIZE).draw(&name, glyphs, &ctx.draw_state, transform, graphics);
+                Text::new_color(WHITE, CHAT_FONT_SIZE)
+                    .draw(&line.text, glyphs, &ctx.draw_state, transform.trans(name_width, 0.), graphics);
+            }
+        }
+
+        // the chat box under the log, with a cursor after the typed text
+        if let Some(ref input) = self.chat_input {
+            if let Some(glyphs) = asset_manager.get_glyphs_mut() {
+                let transform = ctx.transform.trans(10., gui::UI_HEIGHT - 20.);
+                Text::new_color(WHITE, CHAT_FONT_SIZE)
+                    .draw(&format!("> {}_", input), glyphs, &ctx.draw_state, transform, graphics);
+            }
+        }
+
         Ok(())
     }
 
+    fn handle_event(&mut self, event: Event) {
+        if let Some(ref mut input) = self.chat_input {
+            event.text(|text| {
+                let room = MAX_CHAT_LEN.saturating_sub(input.chars().count());
+                input.extend(text.chars().filter(|c| !c.is_control()).take(room));
+            });
+        }
+    }
+
     fn key_press(&mut self, button: Button) {
+        // typed keys belong to the chat box
+        if self.chat_input.is_some() {
+            self.chat_key_press(button);
+
+            return;
+        }
+
+        // spectators have no player to say things as
+        if button == Button::Keyboard(Key::Return) && !self.spectator {
+            self.chat_input = Some(String::new());
+
+            return;
+        }
+
         self.button_tracker.register_press(&button);
 
+        if self.spectator {
+            if button == Button::Keyboard(Key::Tab) {
+                self.watch_next();
+            }
+
+            return;
+        }
+
         if self.player().is_none() {
-            if let Button::Keyboard(key) = button {
-                match key {
-                    Key::Space => {
-                        let spawn_pos = Vec2d::from([400., 300.]);
-                        self.spawn_self_player(spawn_pos);
-                    }
-                    _ => ()
-                };
+            if let Button::Keyboard(Key::Space) = button {
+                self.spawn_self_player(SPAWN_POS);
             }
         }
     }
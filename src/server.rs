@@ -0,0 +1,1008 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{ErrorKind, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use admin::{self, Command};
+use connection::MessageStream;
+use discovery::{self, ServerInfo};
+use handshake::{self, Hello, Welcome, HELLO_LEN, HANDSHAKE_TIMEOUT};
+use protocol::{self, Channel, LobbyPlayer, Message, NetToken, ProtocolError, Sequencer, Color, Vec2d, MAX_CHAT_LEN, MAX_DATAGRAM_LEN, PROTOCOL_VERSION};
+use snapshot::{EntityState, SnapshotHistory, WorldSnapshot};
+use world::{self, Wall};
+
+// how long the server sleeps between two polls of its sockets
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+// the world is simulated and sent to clients 20 times per second
+pub const TICK_INTERVAL: Duration = Duration::from_millis(50);
+// clients ping every second, one that is silent for this long is gone
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+// how long the player of a lost connection waits for its client to come back
+const SESSION_GRACE: Duration = Duration::from_secs(30);
+const DEFAULT_NAME: &str = "side-run server";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayerState {
+    pub name: String,
+    pub pos: Vec2d,
+    pub rotation: f64,
+    pub velocity: Vec2d,
+    pub color: Color
+}
+
+// accepted socket that has not completed the handshake yet
+struct PendingClient {
+    socket: TcpStream,
+    addr: SocketAddr,
+    buf: Vec<u8>,
+    since: Instant
+}
+
+impl PendingClient {
+    // returns None until the whole hello is received
+    fn read_hello(&mut self) -> Result<Option<Hello>, ProtocolError> {
+        let mut buf = [0u8; HELLO_LEN];
+
+        while self.buf.len() < HELLO_LEN {
+            let missing = HELLO_LEN - self.buf.len();
+
+            match self.socket.read(&mut buf[..missing]) {
+                Ok(0) => return Err(ProtocolError::Disconnected),
+                Ok(len) => self.buf.extend_from_slice(&buf[..len]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into())
+            }
+        }
+
+        handshake::decode_hello(&self.buf).map(Some)
+    }
+}
+
+// what a client told about itself in the lobby
+struct Member {
+    name: String,
+    color: Color,
+    ready: bool
+}
+
+struct Client {
+    token: NetToken,
+    // proves the client is the one a session belongs to
+    secret: u64,
+    stream: MessageStream,
+    addr: SocketAddr,
+    // None until the client joins the lobby
+    member: Option<Member>,
+    // None until the client spawns its player
+    player: Option<PlayerState>,
+    // only watches the game, see `Message::Spectate`
+    spectator: bool,
+    // latest position update (input seq, pos, rotation, velocity), applied on the next tick
+    input: Option<(u32, Vec2d, f64, Vec2d)>,
+    // when the player last moved, to know how far it could get since
+    moved_at: Instant,
+    // newest snapshot the client has, deltas are made against it
+    acked_tick: Option<u32>,
+    // where the client receives datagrams, learned from the first message it sends over udp;
+    // the client does that only after its probe was answered, so datagrams get through both ways
+    udp_addr: Option<SocketAddr>,
+    sequencer: Sequencer,
+    last_seen: Instant,
+    // set when the stream broke, the client is removed at the end of the poll
+    error: Option<ProtocolError>
+}
+
+impl Client {
+    fn send(&mut self, udp: &UdpSocket, message: &Message) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if let (Channel::Unreliable, Some(addr)) = (message.channel(), self.udp_addr) {
+            let datagram = protocol::encode_datagram(self.token, self.sequencer.next_seq(), Some(message));
+
+            // a snapshot of a crowded world may not fit, it goes over tcp then
+            if datagram.len() <= MAX_DATAGRAM_LEN {
+                // a lost datagram is just skipped, the next one carries a newer state
+                let _ = udp.send_to(&datagram, addr);
+
+                return;
+            }
+        }
+
+        if let Err(err) = self.stream.send(message) {
+            self.error = Some(err);
+        }
+    }
+}
+
+// what is left of a client that lost its connection, see `Message::Resume`
+struct Session {
+    secret: u64,
+    player: Option<PlayerState>,
+    since: Instant
+}
+
+// Hands out `NetToken`s, gathers clients in the lobby until the host starts the match, relays events of spawned players to every other client
+// and sends them snapshots of the world every tick.
+pub struct Server {
+    // shown to players looking for servers on the local network
+    name: String,
+    listener: TcpListener,
+    // level geometry, positions players report are checked against it
+    map: String,
+    walls: Vec<Wall>,
+    // unreliable messages of clients that support it, bound to the same port as the listener
+    udp: UdpSocket,
+    pending: Vec<PendingClient>,
+    clients: HashMap<NetToken, Client>,
+    sessions: HashMap<NetToken, Session>,
+    session_grace: Duration,
+    // whether the host started the match, later joiners go straight into it
+    started: bool,
+    // the lobby is sent to its members at the end of a poll that changed it
+    lobby_changed: bool,
+    // token 0 is what an offline client uses for itself
+    next_token: NetToken,
+    tick: u32,
+    next_tick: Instant,
+    // snapshots sent recently, bases for the deltas
+    history: SnapshotHistory,
+    // set by the shutdown command, the server stops polling
+    shut_down: bool
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Server, ProtocolError> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        let udp = UdpSocket::bind(listener.local_addr()?)?;
+        udp.set_nonblocking(true)?;
+
+        Ok(Server {
+            name: DEFAULT_NAME.to_string(),
+            listener,
+            map: world::DEFAULT_MAP.to_string(),
+            walls: world::walls(),
+            udp,
+            pending: Vec::new(),
+            clients: HashMap::new(),
+            sessions: HashMap::new(),
+            session_grace: SESSION_GRACE,
+            started: false,
+            lobby_changed: false,
+            next_token: 1,
+            tick: 0,
+            next_tick: Instant::now(),
+            history: SnapshotHistory::new(),
+            shut_down: false
+        })
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    // how long players of lost connections stay in the game waiting for their clients
+    pub fn set_session_grace(&mut self, grace: Duration) {
+        self.session_grace = grace;
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, ProtocolError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn players(&self) -> Vec<(NetToken, PlayerState)> {
+        let mut players: Vec<(NetToken, PlayerState)> = self.clients.iter()
+            .filter_map(|(token, client)| client.player.clone().map(|player| (*token, player)))
+            .collect();
+        players.sort_by_key(|&(token, _)| token);
+
+        players
+    }
+
+    // clients that watch the game without playing it
+    pub fn spectators(&self) -> Vec<NetToken> {
+        let mut spectators: Vec<NetToken> = self.clients.iter()
+            .filter(|&(_, client)| client.spectator)
+            .map(|(token, _)| *token)
+            .collect();
+        spectators.sort();
+
+        spectators
+    }
+
+    // polls until an operator shuts the server down with a command from `console`
+    pub fn run(&mut self, console: Receiver<Command>) {
+        while !self.shut_down {
+            self.poll();
+
+            for command in console.try_iter() {
+                println!("{}", self.execute(&command));
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    // runs the server on its own thread until the returned handle is stopped or dropped
+    pub fn spawn(mut self) -> Result<ServerHandle, ProtocolError> {
+        let addr = self.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let (commands, console) = mpsc::channel::<(Command, Sender<String>)>();
+
+        let thread = {
+            let running = running.clone();
+
+            thread::spawn(move || {
+                while running.load(Ordering::SeqCst) && !self.shut_down {
+                    self.poll();
+
+                    for (command, output) in console.try_iter() {
+                        let _ = output.send(self.execute(&command));
+
+                        // commands sent after the shutdown are left unanswered
+                        if self.shut_down {
+                            break;
+                        }
+                    }
+
+                    thread::sleep(POLL_INTERVAL);
+                }
+            })
+        };
+
+        Ok(ServerHandle { addr, running, commands, thread: Some(thread) })
+    }
+
+    // accepts new clients and handles everything received since the last poll
+    pub fn poll(&mut self) {
+        self.accept_clients();
+        self.process_handshakes();
+
+        let tokens: Vec<NetToken> = self.clients.keys().cloned().collect();
+
+        for token in tokens {
+            let results = match self.clients.get_mut(&token) {
+                Some(client) => {
+                    let results = client.stream.poll_messages();
+
+                    if !results.is_empty() {
+                        client.last_seen = Instant::now();
+                    }
+
+                    results
+                },
+                None => continue
+            };
+
+            for result in results {
+                match result {
+                    Ok(message) => self.handle_message(token, message),
+                    Err(ref err) if !err.is_fatal() => println!("Dropped bad packet from {}: {}", token, err),
+                    Err(err) => if let Some(client) = self.clients.get_mut(&token) {
+                        client.error = Some(err);
+                    }
+                }
+            }
+        }
+
+        self.receive_datagrams();
+
+        let now = Instant::now();
+        if now >= self.next_tick {
+            self.tick();
+
+            // a server that fell behind skips ticks instead of running them in a burst
+            self.next_tick = (self.next_tick + TICK_INTERVAL).max(now);
+        }
+
+        for client in self.clients.values_mut() {
+            if client.error.is_none() && now.duration_since(client.last_seen) >= CLIENT_TIMEOUT {
+                client.error = Some(ProtocolError::TimedOut);
+            }
+        }
+
+        self.remove_broken_clients();
+        self.expire_sessions();
+
+        if self.started && self.clients.is_empty() && self.sessions.is_empty() {
+            println!("Everyone left, the next match starts from the lobby");
+            self.started = false;
+        }
+
+        if mem::replace(&mut self.lobby_changed, false) && !self.started {
+            self.send_lobby();
+        }
+    }
+
+    // runs an admin command, returns what to show to the operator
+    pub fn execute(&mut self, command: &Command) -> String {
+        match *command {
+            Command::List => self.list_clients(),
+            Command::Kick(ref target) => match self.find_client(target) {
+                Some(token) => {
+                    self.close_client(token, "kicked by the server");
+                    format!("kicked {}", token)
+                },
+                None => format!("no client `{}`", target)
+            },
+            Command::Say(ref text) => {
+                let text: String = text.trim().chars().take(MAX_CHAT_LEN).collect();
+                let notice = Message::Notice { text: text.clone() };
+
+                for client in self.clients.values_mut() {
+                    client.send(&self.udp, &notice);
+                }
+
+                format!("server: {}", text)
+            },
+            Command::Map(ref name) => self.change_map(name),
+            Command::Shutdown => {
+                let tokens: Vec<NetToken> = self.clients.keys().cloned().collect();
+
+                for token in tokens {
+                    self.close_client(token, "the server shut down");
+                }
+
+                self.pending.clear();
+                self.sessions.clear();
+                self.shut_down = true;
+
+                "shutting down".to_string()
+            },
+            Command::Help => admin::HELP.to_string()
+        }
+    }
+
+    fn list_clients(&self) -> String {
+        let mut lines = vec![format!("map {}, {} clients, {} lost sessions", self.map, self.clients.len(), self.sessions.len())];
+
+        let mut tokens: Vec<&NetToken> = self.clients.keys().collect();
+        tokens.sort();
+
+        for token in tokens {
+            let client = &self.clients[token];
+            let state = match (client.spectator, client.player.as_ref(), client.member.as_ref()) {
+                (true, _, _) => "spectating".to_string(),
+                (_, Some(player), _) => format!("playing as {}", player.name),
+                (_, None, Some(member)) => format!("in the lobby as {}{}", member.name, if member.ready { ", ready" } else { "" }),
+                (_, None, None) => "connected".to_string()
+            };
+
+            lines.push(format!("{:>4}  {:<21}  {}", token, client.addr, state));
+        }
+
+        let mut tokens: Vec<&NetToken> = self.sessions.keys().collect();
+        tokens.sort();
+
+        for token in tokens {
+            let session = &self.sessions[token];
+            let left = self.session_grace.checked_sub(session.since.elapsed()).unwrap_or_default();
+            let player = match session.player {
+                Some(ref player) => format!(", {} is kept", player.name),
+                None => String::new()
+            };
+
+            lines.push(format!("{:>4}  {:<21}  lost{} for {} s more", token, "-", player, left.as_secs()));
+        }
+
+        lines.join("\n")
+    }
+
+    // the client with the token, or the first one whose player or lobby member has the name
+    fn find_client(&self, target: &str) -> Option<NetToken> {
+        let by_token = target.parse::<NetToken>().ok().filter(|token| self.clients.contains_key(token));
+
+        by_token.or_else(|| {
+            let mut tokens: Vec<NetToken> = self.clients.keys().cloned().collect();
+            tokens.sort();
+
+            tokens.into_iter().find(|token| {
+                let client = &self.clients[token];
+                let player_name = client.player.as_ref().map(|player| &player.name[..]);
+                let member_name = client.member.as_ref().map(|member| &member.name[..]);
+
+                player_name == Some(target) || member_name == Some(target)
+            })
+        })
+    }
+
+    // disconnects the client for good, its session can't be resumed
+    fn close_client(&mut self, token: NetToken, reason: &str) {
+        let mut client = match self.clients.remove(&token) {
+            Some(client) => client,
+            None => return
+        };
+
+        // unread input would make the socket reset instead of closing, and the reason could be lost
+        client.stream.poll_messages();
+        client.send(&self.udp, &Message::Disconnect { reason: reason.to_string() });
+
+        println!("{} ({}) was disconnected: {}", token, client.addr, reason);
+        self.lobby_changed |= client.member.is_some();
+
+        if client.player.is_some() {
+            self.broadcast(token, &Message::Despawn { token });
+        }
+    }
+
+    // everyone's player is gone with the old level, clients spawn again on the new one
+    fn change_map(&mut self, name: &str) -> String {
+        let walls = match world::map(name) {
+            Some(walls) => walls,
+            None => return format!("unknown map `{}`, there are: {}", name, world::MAPS.join(", "))
+        };
+
+        // clients take the map they already have as the one told on connect, and would keep their players
+        if name == self.map {
+            return format!("already on map {}", name);
+        }
+
+        self.map = name.to_string();
+        self.walls = walls;
+
+        let message = Message::Map { name: name.to_string() };
+
+        for client in self.clients.values_mut() {
+            client.player = None;
+            client.input = None;
+            client.send(&self.udp, &message);
+        }
+
+        for session in self.sessions.values_mut() {
+            session.player = None;
+        }
+
+        format!("map changed to {}", name)
+    }
+
+    // applies position updates received since the last tick and sends the new world state
+    fn tick(&mut self) {
+        self.tick = self.tick.wrapping_add(1);
+        let mut snapshot = WorldSnapshot::new(self.tick);
+        let now = Instant::now();
+
+        for (token, client) in self.clients.iter_mut() {
+            if let Some((seq, pos, rotation, velocity)) = client.input.take() {
+                let elapsed = now.duration_since(client.moved_at);
+
+                let pos = match client.player {
+                    Some(ref mut player) => match world::check_move(&self.walls, player.pos, pos, elapsed) {
+                        Ok(_) => {
+                            player.pos = pos;
+                            player.rotation = rotation;
+                            player.velocity = world::clamp_velocity(velocity);
+                            client.moved_at = now;
+
+                            pos
+                        },
+                        // the player stays where it was
+                        Err(err) => {
+                            println!("{} ({}) {}, position corrected", token, player.name, err);
+                            player.velocity = [0., 0.];
+
+                            player.pos
+                        }
+                    },
+                    None => continue
+                };
+
+                // the sender predicts its own movement and corrects it by our answer
+                client.send(&self.udp, &Message::Ack { seq, pos });
+            }
+
+            if let Some(ref player) = client.player {
+                snapshot.entities.insert(*token, EntityState::quantize(player.pos, player.rotation, player.velocity));
+            }
+        }
+
+        let history = &self.history;
+
+        for client in self.clients.values_mut() {
+            let base = client.acked_tick.and_then(|tick| history.get(tick));
+            let delta = snapshot.delta_from(base);
+
+            // the client already knows everything
+            if base.is_some() && delta.is_empty() {
+                continue;
+            }
+
+            client.send(&self.udp, &Message::Snapshot(delta));
+        }
+
+        self.history.push(snapshot);
+    }
+
+    fn receive_datagrams(&mut self) {
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+
+        loop {
+            let (len, addr) = match self.udp.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("Failed to receive datagram: {}", e);
+                    break;
+                }
+            };
+
+            if discovery::is_probe(&buf[..len]) {
+                let players = self.clients.values().filter(|client| !client.spectator).count();
+                let info = ServerInfo { name: self.name.clone(), version: PROTOCOL_VERSION, players: players as u16 };
+
+                if let Err(e) = self.udp.send_to(&discovery::encode_reply(&info), addr) {
+                    println!("Failed to answer discovery probe from {}: {}", addr, e);
+                }
+
+                continue;
+            }
+
+            let (token, seq, message) = match protocol::decode_datagram(&buf[..len]) {
+                Ok(datagram) => datagram,
+                Err(err) => {
+                    println!("Dropped bad datagram from {}: {}", addr, err);
+                    continue;
+                }
+            };
+
+            let fresh = match self.clients.get_mut(&token) {
+                // the token alone is easy to guess, the datagram has to come from the client's host as well
+                Some(client) if client.addr.ip() == addr.ip() => {
+                    client.last_seen = Instant::now();
+                    client.sequencer.accept(seq)
+                },
+                _ => false
+            };
+
+            if !fresh {
+                continue;
+            }
+
+            match message {
+                Some(message) => {
+                    if let Some(client) = self.clients.get_mut(&token) {
+                        client.udp_addr = Some(addr);
+                    }
+
+                    self.handle_message(token, message);
+                },
+                // an empty one is a probe, answered the same way
+                None => if let Some(client) = self.clients.get_mut(&token) {
+                    let datagram = protocol::encode_datagram(token, client.sequencer.next_seq(), None);
+                    let _ = self.udp.send_to(&datagram, addr);
+                }
+            }
+        }
+    }
+
+    fn accept_clients(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((socket, addr)) => match socket.set_nonblocking(true) {
+                    Ok(_) => self.pending.push(PendingClient { socket, addr, buf: vec![], since: Instant::now() }),
+                    Err(e) => println!("Failed to accept {}: {}", addr, e)
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("Failed to accept client: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn process_handshakes(&mut self) {
+        for mut pending in mem::take(&mut self.pending) {
+            match pending.read_hello() {
+                Ok(Some(hello)) => if let Err(err) = self.add_client(pending.socket, pending.addr, hello) {
+                    println!("Handshake with {} failed: {}", pending.addr, err);
+                },
+                Ok(None) => if pending.since.elapsed() < HANDSHAKE_TIMEOUT {
+                    self.pending.push(pending);
+                } else {
+                    println!("Handshake with {} timed out", pending.addr);
+                },
+                Err(err) => println!("Handshake with {} failed: {}", pending.addr, err)
+            }
+        }
+    }
+
+    fn add_client(&mut self, mut socket: TcpStream, addr: SocketAddr, hello: Hello) -> Result<(), ProtocolError> {
+        if let Err(err) = handshake::check_version(hello.version) {
+            // the client is told our version before the socket is closed
+            socket.write_all(&handshake::encode_welcome(&Welcome::reject()))?;
+
+            return Err(err);
+        }
+
+        let token = self.next_token;
+        self.next_token += 1;
+
+        socket.write_all(&handshake::encode_welcome(&Welcome::accept(token, hello.features)))?;
+
+        let mut client = Client {
+            token,
+            secret: new_secret(token),
+            stream: MessageStream::new(socket)?,
+            addr,
+            member: None,
+            player: None,
+            spectator: false,
+            input: None,
+            moved_at: Instant::now(),
+            acked_tick: None,
+            udp_addr: None,
+            sequencer: Sequencer::new(),
+            last_seen: Instant::now(),
+            error: None
+        };
+
+        let session = Message::Session { secret: client.secret };
+        client.send(&self.udp, &session);
+        client.send(&self.udp, &Message::Map { name: self.map.clone() });
+
+        // let the newcomer see everyone who is already playing
+        for (other_token, player) in self.players() {
+            client.send(&self.udp, &Message::Spawn {
+                token: other_token,
+                name: player.name,
+                pos: player.pos,
+                color: player.color
+            });
+        }
+
+        println!("{} connected from {}", token, client.addr);
+        self.clients.insert(token, client);
+
+        Ok(())
+    }
+
+    fn handle_message(&mut self, from: NetToken, message: Message) {
+        let spectator = matches!(self.clients.get(&from), Some(client) if client.spectator);
+
+        // spectators don't take part in the game
+        if spectator && matches!(message, Message::Spawn { .. } | Message::UpdatePos { .. } |
+            Message::Join { .. } | Message::Ready { .. } | Message::Start) {
+            return;
+        }
+
+        // clients can't speak for each other, so the token is always taken from the socket
+        let message = match message {
+            Message::Spawn { name, pos, color, .. } => {
+                let pos = world::spawn_pos(&self.walls, pos);
+                let player = PlayerState { name: name.clone(), pos, rotation: 0., velocity: [0., 0.], color };
+
+                match self.clients.get_mut(&from) {
+                    Some(client) => {
+                        client.player = Some(player);
+                        client.moved_at = Instant::now();
+                    },
+                    None => return
+                }
+
+                println!("{} spawned as {}", from, name);
+
+                Message::Spawn { token: from, name, pos, color }
+            },
+            // others see the new position in the snapshot of the next tick
+            Message::UpdatePos { seq, pos, rotation, velocity, .. } => {
+                // nothing to move before the spawn
+                if let Some(client) = self.clients.get_mut(&from).filter(|client| client.player.is_some()) {
+                    client.input = Some((seq, pos, rotation, velocity));
+                }
+
+                return;
+            },
+            Message::Despawn { .. } => {
+                if let Some(client) = self.clients.get_mut(&from) {
+                    client.input = None;
+                }
+
+                match self.clients.get_mut(&from).and_then(|client| client.player.take()) {
+                    Some(player) => println!("{} ({}) left the game", from, player.name),
+                    None => return
+                }
+
+                Message::Despawn { token: from }
+            },
+            Message::SnapshotAck { tick } => {
+                if let Some(client) = self.clients.get_mut(&from) {
+                    // acks may come out of order over udp
+                    let newer = match client.acked_tick {
+                        Some(acked) => tick.wrapping_sub(acked) as i32 > 0,
+                        None => true
+                    };
+
+                    if newer {
+                        client.acked_tick = Some(tick);
+                    }
+                }
+
+                return;
+            },
+            Message::Ping { id } => {
+                if let Some(client) = self.clients.get_mut(&from) {
+                    client.send(&self.udp, &Message::Pong { id });
+                }
+
+                return;
+            },
+            Message::Resume { token, secret } => {
+                self.resume_session(from, token, secret);
+
+                return;
+            },
+            Message::Join { name, color } => {
+                let client = match self.clients.get_mut(&from) {
+                    Some(client) => client,
+                    None => return
+                };
+
+                println!("{} joined the lobby as {}", from, name);
+                client.member = Some(Member { name, color, ready: false });
+
+                // too late to wait for the others
+                if self.started {
+                    client.send(&self.udp, &Message::Start);
+                }
+
+                self.lobby_changed = true;
+
+                return;
+            },
+            Message::Ready { ready } => {
+                if let Some(member) = self.clients.get_mut(&from).and_then(|client| client.member.as_mut()) {
+                    member.ready = ready;
+                    self.lobby_changed = true;
+                }
+
+                return;
+            },
+            Message::Start => {
+                self.start_match(from);
+
+                return;
+            },
+            Message::Spectate => {
+                // a player can't leave its character behind to watch
+                if let Some(client) = self.clients.get_mut(&from).filter(|client| client.member.is_none() && client.player.is_none()) {
+                    println!("{} is spectating", from);
+                    client.spectator = true;
+                }
+
+                return;
+            },
+            Message::Chat { text, .. } => {
+                let text: String = text.trim().chars().take(MAX_CHAT_LEN).collect();
+
+                // others know the name of a spawned player only
+                let name = match self.clients.get(&from).and_then(|client| client.player.as_ref()) {
+                    Some(player) if !text.is_empty() => player.name.clone(),
+                    _ => return
+                };
+
+                println!("{} ({}): {}", from, name, text);
+
+                Message::Chat { token: from, text }
+            },
+            // only the server acknowledges inputs, sends snapshots, answers pings and hands out sessions
+            Message::Ack { .. } | Message::Snapshot(_) | Message::Pong { .. } |
+            Message::Session { .. } | Message::Resumed { .. } | Message::Lobby { .. } |
+            Message::Notice { .. } | Message::Disconnect { .. } | Message::Map { .. } => return
+        };
+
+        self.broadcast(from, &message);
+    }
+
+    // sends the message to every client except the one it came from
+    fn broadcast(&mut self, from: NetToken, message: &Message) {
+        for (token, client) in self.clients.iter_mut() {
+            if *token != from {
+                client.send(&self.udp, message);
+            }
+        }
+    }
+
+    // the one who can start the match, whoever is in the lobby for the longest time
+    fn host(&self) -> Option<NetToken> {
+        self.clients.iter()
+            .filter(|&(_, client)| client.member.is_some())
+            .map(|(token, _)| *token)
+            .min()
+    }
+
+    fn start_match(&mut self, from: NetToken) {
+        if self.started || self.host() != Some(from) {
+            return;
+        }
+
+        let everyone_ready = self.clients.values()
+            .filter_map(|client| client.member.as_ref())
+            .all(|member| member.ready);
+
+        if !everyone_ready {
+            return;
+        }
+
+        println!("{} started the match", from);
+        self.started = true;
+
+        for client in self.clients.values_mut().filter(|client| client.member.is_some()) {
+            client.send(&self.udp, &Message::Start);
+        }
+    }
+
+    fn send_lobby(&mut self) {
+        let host = match self.host() {
+            Some(host) => host,
+            None => return
+        };
+
+        let mut players: Vec<LobbyPlayer> = self.clients.iter()
+            .filter_map(|(token, client)| client.member.as_ref().map(|member| LobbyPlayer {
+                token: *token,
+                name: member.name.clone(),
+                color: member.color,
+                ready: member.ready
+            }))
+            .collect();
+        players.sort_by_key(|player| player.token);
+
+        let lobby = Message::Lobby { host, players };
+
+        for client in self.clients.values_mut().filter(|client| client.member.is_some()) {
+            client.send(&self.udp, &lobby);
+        }
+    }
+
+    // gives the client `from` the token and the player of a lost connection if it knows the secret
+    fn resume_session(&mut self, from: NetToken, token: NetToken, secret: u64) {
+        let live_secret = self.clients.get(&token).map(|client| client.secret);
+        let lost_secret = self.sessions.get(&token).map(|session| session.secret);
+
+        let restored = if token == from {
+            None
+        } else if live_secret == Some(secret) {
+            // the client gave up on the old connection before we noticed it is broken
+            self.clients.remove(&token).map(|old| {
+                self.lobby_changed |= old.member.is_some();
+                old.player
+            })
+        } else if lost_secret == Some(secret) {
+            self.sessions.remove(&token).map(|session| session.player)
+        } else {
+            None
+        };
+
+        let mut client = match self.clients.remove(&from) {
+            Some(client) => client,
+            None => return
+        };
+
+        let token = match restored {
+            Some(player) => {
+                println!("{} resumed the session of {}", from, token);
+                client.player = player;
+
+                token
+            },
+            None => from
+        };
+
+        client.token = token;
+        client.send(&self.udp, &Message::Resumed { token });
+        self.clients.insert(token, client);
+    }
+
+    fn remove_broken_clients(&mut self) {
+        let broken: Vec<NetToken> = self.clients.iter()
+            .filter(|&(_, client)| client.error.is_some())
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in broken {
+            if let Some(Client { error: Some(err), addr, member, player, secret, .. }) = self.clients.remove(&token) {
+                println!("{} ({}) disconnected: {}", token, addr, err);
+                self.lobby_changed |= member.is_some();
+
+                // the player stays in the game for a while, the client may come back for it
+                self.sessions.insert(token, Session { secret, player, since: Instant::now() });
+            }
+        }
+    }
+
+    fn expire_sessions(&mut self) {
+        let grace = self.session_grace;
+        let expired: Vec<NetToken> = self.sessions.iter()
+            .filter(|&(_, session)| session.since.elapsed() >= grace)
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in expired {
+            if let Some(Session { player: Some(player), .. }) = self.sessions.remove(&token) {
+                println!("{} ({}) did not come back", token, player.name);
+
+                // a client this breaks is removed on the next poll
+                self.broadcast(token, &Message::Despawn { token });
+            }
+        }
+    }
+}
+
+// hard to guess, so a session can't be taken over by someone who only knows the token
+fn new_secret(token: NetToken) -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    token.hash(&mut hasher);
+    Instant::now().hash(&mut hasher);
+
+    hasher.finish()
+}
+
+pub struct ServerHandle {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    // admin commands for the server thread, each with where to send its output
+    commands: Sender<(Command, Sender<String>)>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl ServerHandle {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // where a client on the same machine connects, also when the server listens on all interfaces
+    pub fn loopback_addr(&self) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), self.addr.port())
+    }
+
+    // runs an admin command on the server thread and waits for its output, None if the server is stopped
+    pub fn execute(&self, command: Command) -> Option<String> {
+        let (output, receiver) = mpsc::channel();
+        self.commands.send((command, output)).ok()?;
+
+        receiver.recv().ok()
+    }
+
+    // blocks until the server thread exits, clients are told the server is going away
+    pub fn stop(&mut self) {
+        if self.thread.is_some() {
+            self.execute(Command::Shutdown);
+        }
+
+        self.running.store(false, Ordering::SeqCst);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// a game hosted by one of the players from the client, open to the whole LAN;
+// it stops when the handle is dropped
+pub fn host(port: u16, name: String) -> Result<ServerHandle, ProtocolError> {
+    let mut server = Server::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    server.set_name(name);
+
+    server.spawn()
+}
@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::f64::consts::PI;
+use protocol::{NetToken, ProtocolError, Vec2d};
+
+// positions are sent in 1/POS_SCALE pixel steps, rotation in 1/65536 of a full turn,
+// velocities in 1/POS_SCALE pixel per second steps
+pub const POS_SCALE: f64 = 8.;
+// snapshots older than this many ticks can't be a delta base anymore
+const MAX_HISTORY: usize = 64;
+
+// Quantized state of a player as it goes over the wire
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntityState {
+    pub x: i32,
+    pub y: i32,
+    pub rotation: u16,
+    pub velocity: [i16; 2]
+}
+
+impl EntityState {
+    pub fn quantize(pos: Vec2d, rotation: f64, velocity: Vec2d) -> EntityState {
+        let turns = (rotation / (2. * PI)).rem_euclid(1.);
+
+        EntityState {
+            x: (pos[0] * POS_SCALE).round() as i32,
+            y: (pos[1] * POS_SCALE).round() as i32,
+            // a full turn wraps around to 0
+            rotation: (turns * 65536.).round() as u32 as u16,
+            // `as` saturates, nothing legal moves that fast anyway
+            velocity: [(velocity[0] * POS_SCALE).round() as i16, (velocity[1] * POS_SCALE).round() as i16]
+        }
+    }
+
+    // position, rotation and velocity; rotation is returned in -PI..PI like `atan2` gives it
+    pub fn dequantize(&self) -> (Vec2d, f64, Vec2d) {
+        let mut rotation = f64::from(self.rotation) / 65536. * 2. * PI;
+
+        if rotation > PI {
+            rotation -= 2. * PI;
+        }
+
+        let pos = [f64::from(self.x) / POS_SCALE, f64::from(self.y) / POS_SCALE];
+        let velocity = [f64::from(self.velocity[0]) / POS_SCALE, f64::from(self.velocity[1]) / POS_SCALE];
+
+        (pos, rotation, velocity)
+    }
+}
+
+// Fields of an entity that changed since the base snapshot, all of them for a new one
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityDelta {
+    pub x: Option<i32>,
+    pub y: Option<i32>,
+    pub rotation: Option<u16>,
+    pub velocity: Option<[i16; 2]>
+}
+
+impl EntityDelta {
+    fn between(base: Option<&EntityState>, state: &EntityState) -> Option<EntityDelta> {
+        let delta = EntityDelta {
+            x: changed(base.map(|base| base.x), state.x),
+            y: changed(base.map(|base| base.y), state.y),
+            rotation: changed(base.map(|base| base.rotation), state.rotation),
+            velocity: changed(base.map(|base| base.velocity), state.velocity)
+        };
+
+        if delta.x.is_none() && delta.y.is_none() && delta.rotation.is_none() && delta.velocity.is_none() {
+            None
+        } else {
+            Some(delta)
+        }
+    }
+
+    fn apply(&self, base: Option<&EntityState>) -> Result<EntityState, ProtocolError> {
+        Ok(EntityState {
+            x: field_or_base(self.x, base.map(|base| base.x), "x")?,
+            y: field_or_base(self.y, base.map(|base| base.y), "y")?,
+            rotation: field_or_base(self.rotation, base.map(|base| base.rotation), "rotation")?,
+            velocity: field_or_base(self.velocity, base.map(|base| base.velocity), "velocity")?
+        })
+    }
+}
+
+fn changed<T: PartialEq + Copy>(base_value: Option<T>, value: T) -> Option<T> {
+    if base_value == Some(value) {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn field_or_base<T>(value: Option<T>, base_value: Option<T>, name: &'static str) -> Result<T, ProtocolError> {
+    value.or(base_value).ok_or(ProtocolError::MalformedField(name))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorldSnapshot {
+    pub tick: u32,
+    pub entities: BTreeMap<NetToken, EntityState>
+}
+
+impl WorldSnapshot {
+    pub fn new(tick: u32) -> WorldSnapshot {
+        WorldSnapshot { tick, entities: BTreeMap::new() }
+    }
+
+    // what changed since `base`, or the whole snapshot if there is no base
+    pub fn delta_from(&self, base: Option<&WorldSnapshot>) -> SnapshotDelta {
+        let changed = self.entities.iter()
+            .filter_map(|(token, state)| {
+                let base_state = base.and_then(|base| base.entities.get(token));
+
+                EntityDelta::between(base_state, state).map(|delta| (*token, delta))
+            })
+            .collect();
+
+        let removed = match base {
+            Some(base) => base.entities.keys()
+                .filter(|token| !self.entities.contains_key(token))
+                .cloned()
+                .collect(),
+            None => vec![]
+        };
+
+        SnapshotDelta { tick: self.tick, base_tick: base.map(|base| base.tick), changed, removed }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotDelta {
+    pub tick: u32,
+    // None for a full snapshot
+    pub base_tick: Option<u32>,
+    pub changed: Vec<(NetToken, EntityDelta)>,
+    pub removed: Vec<NetToken>
+}
+
+impl SnapshotDelta {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+// Recent snapshots of the world: the server keeps the ones it sent, a client the ones it received,
+// so both sides have the base snapshot of every delta the client acknowledged.
+pub struct SnapshotHistory {
+    snapshots: VecDeque<WorldSnapshot>
+}
+
+impl SnapshotHistory {
+    pub fn new() -> SnapshotHistory {
+        SnapshotHistory { snapshots: VecDeque::new() }
+    }
+
+    pub fn get(&self, tick: u32) -> Option<&WorldSnapshot> {
+        self.snapshots.iter().find(|snapshot| snapshot.tick == tick)
+    }
+
+    pub fn latest(&self) -> Option<&WorldSnapshot> {
+        self.snapshots.back()
+    }
+
+    pub fn push(&mut self, snapshot: WorldSnapshot) {
+        if self.snapshots.len() == MAX_HISTORY {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back(snapshot);
+    }
+
+    // rebuilds the snapshot from its delta and remembers it,
+    // returns None for a snapshot that is not newer than the latest one
+    pub fn apply(&mut self, delta: &SnapshotDelta) -> Result<Option<&WorldSnapshot>, ProtocolError> {
+        if let Some(latest) = self.latest() {
+            if delta.tick.wrapping_sub(latest.tick) as i32 <= 0 {
+                return Ok(None);
+            }
+        }
+
+        let mut snapshot = match delta.base_tick {
+            Some(base_tick) => match self.get(base_tick) {
+                Some(base) => base.clone(),
+                // the base was never received or is too old, the server will send a full snapshot
+                None => return Err(ProtocolError::UnknownBase(base_tick))
+            },
+            None => WorldSnapshot::new(delta.tick)
+        };
+
+        snapshot.tick = delta.tick;
+
+        for token in delta.removed.iter() {
+            snapshot.entities.remove(token);
+        }
+
+        for &(token, ref entity) in delta.changed.iter() {
+            let state = entity.apply(snapshot.entities.get(&token))?;
+            snapshot.entities.insert(token, state);
+        }
+
+        self.push(snapshot);
+
+        Ok(self.latest())
+    }
+}
+
+impl Default for SnapshotHistory {
+    fn default() -> SnapshotHistory {
+        SnapshotHistory::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world(tick: u32, entities: &[(NetToken, i32, i32, u16)]) -> WorldSnapshot {
+        let mut snapshot = WorldSnapshot::new(tick);
+
+        for &(token, x, y, rotation) in entities.iter() {
+            snapshot.entities.insert(token, EntityState { x, y, rotation, velocity: [0, 0] });
+        }
+
+        snapshot
+    }
+
+    #[test]
+    fn quantization_keeps_precision() {
+        let (pos, rotation, velocity) = EntityState::quantize([123.45, -67.89], -2.5, [-150.3, 42.]).dequantize();
+
+        assert!((pos[0] - 123.45).abs() <= 0.5 / POS_SCALE);
+        assert!((pos[1] + 67.89).abs() <= 0.5 / POS_SCALE);
+        assert!((rotation + 2.5).abs() < 1e-4);
+        assert!((velocity[0] + 150.3).abs() <= 0.5 / POS_SCALE);
+        assert_eq!(velocity[1], 42.);
+        assert_eq!(EntityState::quantize([0., 0.], 2. * PI, [0., 0.]).rotation, 0);
+        assert_eq!(EntityState::quantize([0., 0.], 0., [1e9, -1e9]).velocity, [i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn unchanged_entities_are_left_out() {
+        let base = world(1, &[(1, 10, 10, 0), (2, 20, 20, 0)]);
+        let current = world(2, &[(1, 10, 10, 0), (2, 25, 20, 0)]);
+
+        let delta = current.delta_from(Some(&base));
+
+        assert_eq!(delta.base_tick, Some(1));
+        assert_eq!(delta.changed, vec![(2, EntityDelta { x: Some(25), y: None, rotation: None, velocity: None })]);
+        assert!(delta.removed.is_empty());
+    }
+
+    #[test]
+    fn delta_restores_snapshot() {
+        let base = world(1, &[(1, 10, 10, 0), (2, 20, 20, 0)]);
+        let current = world(2, &[(2, 20, 30, 5), (3, 1, 1, 1)]);
+        let mut history = SnapshotHistory::new();
+
+        assert_eq!(history.apply(&base.delta_from(None)).unwrap(), Some(&base));
+
+        let delta = current.delta_from(Some(&base));
+        assert_eq!(delta.removed, vec![1]);
+        assert_eq!(history.apply(&delta).unwrap(), Some(&current));
+    }
+
+    #[test]
+    fn new_entity_needs_every_field() {
+        let delta = SnapshotDelta {
+            tick: 1,
+            base_tick: None,
+            changed: vec![(1, EntityDelta { x: Some(1), y: None, rotation: Some(0), velocity: Some([0, 0]) })],
+            removed: vec![]
+        };
+
+        assert_eq!(SnapshotHistory::new().apply(&delta), Err(ProtocolError::MalformedField("y")));
+    }
+
+    #[test]
+    fn unknown_base_is_rejected() {
+        let delta = world(2, &[]).delta_from(Some(&world(1, &[])));
+
+        let err = SnapshotHistory::new().apply(&delta).unwrap_err();
+
+        assert_eq!(err, ProtocolError::UnknownBase(1));
+        assert!(!err.is_fatal());
+    }
+
+    #[test]
+    fn old_snapshot_is_ignored() {
+        let mut history = SnapshotHistory::new();
+        history.apply(&world(5, &[]).delta_from(None)).unwrap();
+
+        assert_eq!(history.apply(&world(4, &[(1, 0, 0, 0)]).delta_from(None)), Ok(None));
+        assert_eq!(history.latest().map(|snapshot| snapshot.tick), Some(5));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut history = SnapshotHistory::new();
+
+        for tick in 0..MAX_HISTORY as u32 * 2 {
+            history.push(WorldSnapshot::new(tick));
+        }
+
+        assert_eq!(history.snapshots.len(), MAX_HISTORY);
+        assert!(history.get(0).is_none());
+    }
+}
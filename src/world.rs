@@ -0,0 +1,235 @@
+// Level geometry and movement rules, shared by the client that predicts movement
+// and the server that has the final word on it.
+
+use std::fmt;
+use std::time::Duration;
+use protocol::{Color, Vec2d};
+
+pub const PLAYER_SPEED: f64 = 200.;
+// the level a server starts with
+pub const DEFAULT_MAP: &str = "default";
+pub const MAPS: [&str; 2] = [DEFAULT_MAP, "arena"];
+// where a player that asked to spawn inside a wall appears instead
+pub const SPAWN_POS: Vec2d = [400., 300.];
+// updates come in bursts over a lossy network, so a move may look a bit faster than it was
+const SPEED_TOLERANCE: f64 = 1.5;
+// distance any move may have, covers rounding and the frame a key was released on
+const MOVE_SLACK: f64 = 10.;
+// standing still for long does not allow a teleport
+const MAX_MOVE_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wall {
+    pub pos: Vec2d,
+    pub half_size: Vec2d,
+    pub color: Color
+}
+
+impl Wall {
+    fn new(x: f64, y: f64, half_width: f64, half_height: f64, color: Color) -> Wall {
+        Wall { pos: [x, y], half_size: [half_width, half_height], color }
+    }
+
+    // same bounds as the client collision uses: left and top edges are inside, right and bottom are not
+    pub fn contains(&self, point: Vec2d) -> bool {
+        (0..2).all(|axis| {
+            let offset = point[axis] - self.pos[axis];
+
+            offset >= -self.half_size[axis] && offset < self.half_size[axis]
+        })
+    }
+
+    // whether the straight way from `from` to `to` goes through the inside of the wall
+    pub fn blocks(&self, from: Vec2d, to: Vec2d) -> bool {
+        let mut enter: f64 = 0.;
+        let mut exit: f64 = 1.;
+
+        for axis in 0..2 {
+            let min = self.pos[axis] - self.half_size[axis];
+            let max = self.pos[axis] + self.half_size[axis];
+            let delta = to[axis] - from[axis];
+
+            if delta == 0. {
+                if from[axis] <= min || from[axis] >= max {
+                    return false;
+                }
+
+                continue;
+            }
+
+            let (t0, t1) = ((min - from[axis]) / delta, (max - from[axis]) / delta);
+            enter = enter.max(t0.min(t1));
+            exit = exit.min(t0.max(t1));
+        }
+
+        // touching an edge is not going through
+        enter < exit
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IllegalMove {
+    TooFast { distance: f64, allowed: f64 },
+    ThroughWall
+}
+
+impl fmt::Display for IllegalMove {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IllegalMove::TooFast { distance, allowed } => write!(f, "moved {:.1} px, only {:.1} allowed", distance, allowed),
+            IllegalMove::ThroughWall => write!(f, "went through a wall")
+        }
+    }
+}
+
+// checks a move a player reported `elapsed` after the previous accepted one
+pub fn check_move(walls: &[Wall], from: Vec2d, to: Vec2d, elapsed: Duration) -> Result<(), IllegalMove> {
+    let seconds = elapsed.min(MAX_MOVE_TIME).as_secs_f64();
+    let allowed = PLAYER_SPEED * seconds * SPEED_TOLERANCE + MOVE_SLACK;
+    let distance = ((to[0] - from[0]).powi(2) + (to[1] - from[1]).powi(2)).sqrt();
+
+    if distance > allowed {
+        return Err(IllegalMove::TooFast { distance, allowed });
+    }
+
+    if walls.iter().any(|wall| wall.blocks(from, to) || wall.contains(to)) {
+        return Err(IllegalMove::ThroughWall);
+    }
+
+    Ok(())
+}
+
+// others extrapolate with the reported velocity, so it can't be faster than a legal move
+pub fn clamp_velocity(velocity: Vec2d) -> Vec2d {
+    let max = PLAYER_SPEED * SPEED_TOLERANCE;
+    let speed = (velocity[0].powi(2) + velocity[1].powi(2)).sqrt();
+
+    if speed > max {
+        [velocity[0] * max / speed, velocity[1] * max / speed]
+    } else {
+        velocity
+    }
+}
+
+// where a player asking for `pos` is spawned
+pub fn spawn_pos(walls: &[Wall], pos: Vec2d) -> Vec2d {
+    if walls.iter().any(|wall| wall.contains(pos)) {
+        SPAWN_POS
+    } else {
+        pos
+    }
+}
+
+// walls of the level called `name`
+pub fn map(name: &str) -> Option<Vec<Wall>> {
+    match name {
+        DEFAULT_MAP => Some(walls()),
+        "arena" => Some(arena()),
+        _ => None
+    }
+}
+
+// the default level
+pub fn walls() -> Vec<Wall> {
+    let white = [1., 1., 1., 1.];
+    let red = [1., 0., 0., 1.];
+    let green = [0., 1., 0., 1.];
+    let blue = [0., 0., 1., 1.];
+
+    vec![
+        Wall::new(200., 300., 100., 10., white),
+        Wall::new(500., 100., 10., 100., red),
+        Wall::new(50., 40., 100., 100., green),
+        Wall::new(600., 600., 100., 150., blue),
+        Wall::new(50., 500., 50., 50., blue),
+        Wall::new(50., 650., 50., 50., white),
+        Wall::new(200., 500., 50., 50., red),
+        Wall::new(200., 650., 50., 50., green)
+    ]
+}
+
+fn arena() -> Vec<Wall> {
+    let white = [1., 1., 1., 1.];
+    let red = [1., 0., 0., 1.];
+    let green = [0., 1., 0., 1.];
+    let blue = [0., 0., 1., 1.];
+
+    vec![
+        Wall::new(400., 0., 300., 10., white),
+        Wall::new(400., 600., 300., 10., white),
+        Wall::new(250., 150., 40., 40., red),
+        Wall::new(550., 150., 40., 40., green),
+        Wall::new(250., 450., 40., 40., blue),
+        Wall::new(550., 450., 40., 40., red)
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wall() -> Wall {
+        Wall::new(0., 0., 10., 10., [1., 1., 1., 1.])
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn walls_block_crossing_moves_only() {
+        let wall = wall();
+
+        assert!(wall.blocks([-20., 0.], [20., 0.]));
+        assert!(wall.blocks([-20., -20.], [20., 20.]));
+        assert!(!wall.blocks([-20., 15.], [20., 15.]));
+        assert!(!wall.blocks([-20., 0.], [-11., 0.]));
+        // sliding along an edge
+        assert!(!wall.blocks([-20., 10.], [20., 10.]));
+    }
+
+    #[test]
+    fn fast_move_is_illegal() {
+        let walls = vec![];
+
+        assert_eq!(check_move(&walls, [0., 0.], [20., 0.], ms(100)), Ok(()));
+        assert!(matches!(check_move(&walls, [0., 0.], [100., 0.], ms(100)), Err(IllegalMove::TooFast { .. })));
+        // waiting does not pile up an allowance for a teleport
+        assert!(matches!(check_move(&walls, [0., 0.], [1000., 0.], ms(10_000)), Err(IllegalMove::TooFast { .. })));
+    }
+
+    #[test]
+    fn move_through_wall_is_illegal() {
+        let walls = vec![wall()];
+
+        assert_eq!(check_move(&walls, [-20., 0.], [20., 0.], ms(1000)), Err(IllegalMove::ThroughWall));
+        assert_eq!(check_move(&walls, [-20., 0.], [-5., 0.], ms(1000)), Err(IllegalMove::ThroughWall));
+        assert_eq!(check_move(&walls, [-20., 0.], [-10.5, 0.], ms(1000)), Ok(()));
+    }
+
+    #[test]
+    fn velocity_is_clamped_to_legal_speed() {
+        assert_eq!(clamp_velocity([100., -100.]), [100., -100.]);
+        assert_eq!(clamp_velocity([0., 1000.]), [0., PLAYER_SPEED * SPEED_TOLERANCE]);
+    }
+
+    #[test]
+    fn spawn_inside_wall_is_moved() {
+        let walls = walls();
+
+        assert_eq!(spawn_pos(&walls, [0., 0.]), SPAWN_POS);
+        assert_eq!(spawn_pos(&walls, [450., 300.]), [450., 300.]);
+        assert!(!walls.iter().any(|wall| wall.contains(SPAWN_POS)));
+    }
+
+    #[test]
+    fn every_map_has_a_free_spawn() {
+        for name in MAPS.iter() {
+            let walls = map(name).unwrap();
+
+            assert!(!walls.iter().any(|wall| wall.contains(SPAWN_POS)), "{} spawns inside a wall", name);
+        }
+
+        assert_eq!(map("nowhere"), None);
+    }
+}
@@ -0,0 +1,1205 @@
+extern crate side_run;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+use side_run::admin::Command;
+use side_run::discovery::{Browser, ServerInfo};
+use side_run::connection::{ConnectEvent, Connection, Connector, MessageStream, NetConfig, Session};
+use side_run::handshake::{self, Hello, Welcome, FEATURE_UDP, HELLO_LEN, WELCOME_LEN};
+use side_run::netsim::NetConditions;
+use side_run::protocol::{self, LobbyPlayer, Message, NetToken, ProtocolError, MAX_CHAT_LEN, PROTOCOL_VERSION};
+use side_run::server::{self, Server, ServerHandle};
+use side_run::snapshot::{SnapshotHistory, WorldSnapshot};
+use side_run::world;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+// players of lost connections are despawned quickly, so tests don't wait for long
+const SESSION_GRACE: Duration = Duration::from_millis(300);
+
+fn start_server() -> ServerHandle {
+    let mut server = Server::bind("127.0.0.1:0").unwrap();
+    server.set_session_grace(SESSION_GRACE);
+
+    server.spawn().unwrap()
+}
+
+fn connect(addr: SocketAddr) -> Connection {
+    Connection::new(TcpStream::connect(addr).unwrap()).unwrap()
+}
+
+fn spawn(connection: &mut Connection, name: &str, pos: [f64; 2]) {
+    connection.send_spawn_event(name.to_string(), pos, [1., 0., 0., 1.]).unwrap();
+}
+
+// snapshots are sent every tick and the map on every connect, tests wait for them on their own
+fn is_background(message: &Message) -> bool {
+    matches!(*message, Message::Snapshot(_) | Message::Map { .. })
+}
+
+// polls until `count` messages arrived or the timeout expired, background messages are skipped
+fn receive(connection: &mut Connection, count: usize) -> Vec<Message> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut messages = vec![];
+
+    while messages.len() < count && Instant::now() < deadline {
+        for result in connection.poll_messages() {
+            let message = result.expect("valid message");
+
+            if !is_background(&message) {
+                messages.push(message);
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    messages
+}
+
+// gives the server some time and checks that nothing but background messages was sent to the client
+fn assert_silent(connection: &mut Connection) {
+    thread::sleep(Duration::from_millis(100));
+
+    let results: Vec<_> = connection.poll_messages().into_iter()
+        .filter(|result| result.as_ref().map(|message| !is_background(message)).unwrap_or(true))
+        .collect();
+    assert!(results.is_empty(), "unexpected {:?}", results);
+}
+
+// applies and acknowledges received snapshots until the world satisfies `done`
+fn watch_world<F: Fn(&WorldSnapshot) -> bool>(connection: &mut Connection, history: &mut SnapshotHistory, done: F) -> WorldSnapshot {
+    let deadline = Instant::now() + TIMEOUT;
+
+    while Instant::now() < deadline {
+        for result in connection.poll_messages() {
+            if let Message::Snapshot(delta) = result.expect("valid message") {
+                let world = match history.apply(&delta).expect("valid snapshot") {
+                    Some(world) => world.clone(),
+                    None => continue
+                };
+
+                connection.send(&Message::SnapshotAck { tick: world.tick }).unwrap();
+
+                if done(&world) {
+                    return world;
+                }
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    panic!("world did not change as expected, latest {:?}", history.latest());
+}
+
+fn join(connection: &mut Connection, name: &str) {
+    connection.send(&Message::Join { name: name.to_string(), color: [0., 1., 0., 1.] }).unwrap();
+}
+
+// polls until the lobby satisfies `done`, returns its host and players
+fn watch_lobby<F: Fn(NetToken, &[LobbyPlayer]) -> bool>(connection: &mut Connection, done: F) -> (NetToken, Vec<LobbyPlayer>) {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut latest = None;
+
+    while Instant::now() < deadline {
+        for result in connection.poll_messages() {
+            if let Message::Lobby { host, players } = result.expect("valid message") {
+                if done(host, &players) {
+                    return (host, players);
+                }
+
+                latest = Some((host, players));
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    panic!("lobby did not change as expected, latest {:?}", latest);
+}
+
+fn is_ready(players: &[LobbyPlayer]) -> bool {
+    players.iter().all(|player| player.ready)
+}
+
+fn position_in(world: &WorldSnapshot, token: usize) -> Option<[f64; 2]> {
+    world.entities.get(&token).map(|state| state.dequantize().0)
+}
+
+#[test]
+fn clients_get_distinct_tokens() {
+    let server = start_server();
+    let first = connect(server.addr());
+    let second = connect(server.addr());
+
+    assert!(first.token != 0);
+    assert!(second.token != 0);
+    assert!(first.token != second.token);
+}
+
+#[test]
+fn spawn_is_relayed_to_other_clients() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+    let mut third = connect(server.addr());
+
+    spawn(&mut first, "first", [400., 300.]);
+
+    let expected = Message::Spawn { token: first.token, name: "first".to_string(), pos: [400., 300.], color: [1., 0., 0., 1.] };
+    assert_eq!(receive(&mut second, 1), vec![expected.clone()]);
+    assert_eq!(receive(&mut third, 1), vec![expected]);
+    assert_silent(&mut first);
+}
+
+#[test]
+fn server_assigns_sender_token() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    first.send(&Message::Spawn { token: 999, name: "liar".to_string(), pos: [0., 0.], color: [1., 1., 1., 1.] }).unwrap();
+
+    match receive(&mut second, 1).pop() {
+        Some(Message::Spawn { token, .. }) => assert_eq!(token, first.token),
+        other => panic!("unexpected {:?}", other)
+    }
+}
+
+#[test]
+fn position_updates_reach_others_in_snapshots() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+    let mut history = SnapshotHistory::new();
+
+    spawn(&mut first, "first", [400., 300.]);
+    watch_world(&mut second, &mut history, |world| position_in(world, first.token) == Some([400., 300.]));
+
+    for i in 1..11 {
+        first.send_update_pos_event(i, [400. + i as f64, 305.], 0.5, [world::PLAYER_SPEED, 0.]).unwrap();
+    }
+
+    let world = watch_world(&mut second, &mut history, |world| position_in(world, first.token) == Some([410., 305.]));
+    let (_, rotation, velocity) = world.entities[&first.token].dequantize();
+    assert!((rotation - 0.5).abs() < 1e-3);
+    assert_eq!(velocity, [world::PLAYER_SPEED, 0.]);
+}
+
+#[test]
+fn reported_velocity_is_limited() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+    let mut history = SnapshotHistory::new();
+
+    spawn(&mut first, "first", [400., 300.]);
+    first.send_update_pos_event(1, [405., 300.], 0., [100_000., 0.]).unwrap();
+
+    let world = watch_world(&mut second, &mut history, |world| position_in(world, first.token) == Some([405., 300.]));
+    let (_, _, velocity) = world.entities[&first.token].dequantize();
+    assert!(velocity[0] < world::PLAYER_SPEED * 2.);
+}
+
+#[test]
+fn unchanged_world_is_not_sent_again() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+    let mut history = SnapshotHistory::new();
+
+    spawn(&mut first, "first", [400., 300.]);
+    watch_world(&mut second, &mut history, |world| world.entities.contains_key(&first.token));
+
+    // the delta against what the client acknowledged is empty
+    thread::sleep(Duration::from_millis(200));
+    let results: Vec<_> = second.poll_messages().into_iter()
+        .filter_map(|result| result.ok())
+        .filter_map(|message| match message {
+            Message::Snapshot(delta) => Some(delta),
+            _ => None
+        })
+        .filter(|delta| delta.base_tick.is_some())
+        .collect();
+    assert!(results.iter().all(|delta| delta.is_empty()), "unexpected {:?}", results);
+    assert!(results.len() <= 1);
+}
+
+#[test]
+fn despawned_player_leaves_snapshots() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+    let mut history = SnapshotHistory::new();
+
+    spawn(&mut first, "first", [400., 300.]);
+    watch_world(&mut second, &mut history, |world| world.entities.contains_key(&first.token));
+
+    first.send(&Message::Despawn { token: first.token }).unwrap();
+
+    watch_world(&mut second, &mut history, |world| world.entities.is_empty());
+}
+
+#[test]
+fn position_updates_are_acknowledged_to_sender() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+
+    spawn(&mut first, "first", [400., 300.]);
+    first.send_update_pos_event(1, [405., 300.], 0., [0., 0.]).unwrap();
+    first.send_update_pos_event(2, [410., 300.], 0., [0., 0.]).unwrap();
+
+    // both updates may be applied on the same tick, then only the latest one is acknowledged
+    let mut acks = vec![];
+    while acks.last() != Some(&Message::Ack { seq: 2, pos: [410., 300.] }) {
+        match receive(&mut first, 1).pop() {
+            Some(ack) => acks.push(ack),
+            None => panic!("no ack for the latest update, got {:?}", acks)
+        }
+    }
+
+    assert!(acks.len() == 1 || acks[0] == Message::Ack { seq: 1, pos: [405., 300.] }, "unexpected {:?}", acks);
+}
+
+#[test]
+fn too_fast_move_is_corrected() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+
+    spawn(&mut first, "first", [400., 300.]);
+    first.send_update_pos_event(1, [900., 300.], 0., [0., 0.]).unwrap();
+
+    assert_eq!(receive(&mut first, 1), vec![Message::Ack { seq: 1, pos: [400., 300.] }]);
+}
+
+#[test]
+fn move_through_wall_is_corrected() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+
+    // right above the wall at (100..300, 290..310)
+    spawn(&mut first, "first", [200., 280.]);
+    thread::sleep(Duration::from_millis(300));
+    first.send_update_pos_event(1, [200., 320.], 0., [0., 0.]).unwrap();
+
+    assert_eq!(receive(&mut first, 1), vec![Message::Ack { seq: 1, pos: [200., 280.] }]);
+
+    // moving along it is fine
+    first.send_update_pos_event(2, [260., 280.], 0., [0., 0.]).unwrap();
+    assert_eq!(receive(&mut first, 1), vec![Message::Ack { seq: 2, pos: [260., 280.] }]);
+}
+
+#[test]
+fn spawn_inside_wall_is_moved_out() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    spawn(&mut first, "first", [50., 40.]);
+
+    match receive(&mut second, 1).pop() {
+        Some(Message::Spawn { pos, .. }) => assert_eq!(pos, world::SPAWN_POS),
+        other => panic!("unexpected {:?}", other)
+    }
+}
+
+#[test]
+fn position_update_before_spawn_is_ignored() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    first.send_update_pos_event(1, [10., 10.], 0., [0., 0.]).unwrap();
+
+    assert_silent(&mut second);
+}
+
+#[test]
+fn chat_is_relayed_to_other_clients() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    spawn(&mut first, "first", [400., 300.]);
+    assert_eq!(receive(&mut second, 1).len(), 1);
+
+    first.send_chat("  hello there ".to_string()).unwrap();
+
+    assert_eq!(receive(&mut second, 1), vec![Message::Chat { token: first.token, text: "hello there".to_string() }]);
+    assert_silent(&mut first);
+}
+
+#[test]
+fn long_chat_is_cut() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    spawn(&mut first, "first", [400., 300.]);
+    assert_eq!(receive(&mut second, 1).len(), 1);
+
+    first.send_chat("ы".repeat(MAX_CHAT_LEN * 2)).unwrap();
+
+    match receive(&mut second, 1).pop() {
+        Some(Message::Chat { text, .. }) => assert_eq!(text, "ы".repeat(MAX_CHAT_LEN)),
+        other => panic!("unexpected {:?}", other)
+    }
+}
+
+#[test]
+fn chat_without_player_is_dropped() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    first.send_chat("anyone?".to_string()).unwrap();
+    assert_silent(&mut second);
+
+    spawn(&mut first, "first", [400., 300.]);
+    assert_eq!(receive(&mut second, 1).len(), 1);
+
+    // nothing but whitespace is not worth relaying either
+    first.send_chat("   ".to_string()).unwrap();
+    assert_silent(&mut second);
+}
+
+#[test]
+fn lobby_lists_joined_players() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+    let mut watcher = connect(server.addr());
+
+    join(&mut first, "first");
+    join(&mut second, "second");
+
+    let (host, players) = watch_lobby(&mut first, |_, players| players.len() == 2);
+    assert_eq!(host, first.token);
+    assert_eq!(players[1], LobbyPlayer { token: second.token, name: "second".to_string(), color: [0., 1., 0., 1.], ready: false });
+
+    second.send(&Message::Ready { ready: true }).unwrap();
+    watch_lobby(&mut first, |_, players| players[1].ready);
+
+    // only those in the lobby hear about it
+    assert_silent(&mut watcher);
+}
+
+#[test]
+fn host_starts_match_when_everyone_is_ready() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    join(&mut first, "first");
+    join(&mut second, "second");
+    watch_lobby(&mut second, |_, players| players.len() == 2);
+
+    // nobody is ready yet
+    first.send(&Message::Start).unwrap();
+    first.send(&Message::Ready { ready: true }).unwrap();
+    second.send(&Message::Ready { ready: true }).unwrap();
+    watch_lobby(&mut first, |_, players| is_ready(players));
+    watch_lobby(&mut second, |_, players| is_ready(players));
+    assert_silent(&mut second);
+
+    // and only the host can start
+    second.send(&Message::Start).unwrap();
+    assert_silent(&mut first);
+
+    first.send(&Message::Start).unwrap();
+    assert_eq!(receive(&mut first, 1), vec![Message::Start]);
+    assert_eq!(receive(&mut second, 1), vec![Message::Start]);
+}
+
+#[test]
+fn late_joiner_goes_straight_into_match() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+
+    join(&mut first, "first");
+    first.send(&Message::Ready { ready: true }).unwrap();
+    watch_lobby(&mut first, |_, players| is_ready(players));
+    first.send(&Message::Start).unwrap();
+    assert_eq!(receive(&mut first, 1), vec![Message::Start]);
+
+    let mut late = connect(server.addr());
+    join(&mut late, "late");
+
+    assert_eq!(receive(&mut late, 1), vec![Message::Start]);
+    assert_silent(&mut first);
+}
+
+#[test]
+fn host_leaving_passes_lobby_on() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    join(&mut first, "first");
+    join(&mut second, "second");
+    watch_lobby(&mut second, |_, players| players.len() == 2);
+
+    drop(first);
+
+    let (host, players) = watch_lobby(&mut second, |_, players| players.len() == 1);
+    assert_eq!(host, second.token);
+    assert_eq!(players[0].token, second.token);
+}
+
+#[test]
+fn spectator_watches_without_playing() {
+    let server = start_server();
+    let mut spectator = connect(server.addr());
+    let mut player = connect(server.addr());
+    let mut history = SnapshotHistory::new();
+
+    spectator.send(&Message::Spectate).unwrap();
+    spawn(&mut player, "player", [400., 300.]);
+
+    assert_eq!(receive(&mut spectator, 1).len(), 1);
+    watch_world(&mut spectator, &mut history, |world| position_in(world, player.token) == Some([400., 300.]));
+
+    // neither a player nor a lobby member shows up for the others
+    spawn(&mut spectator, "sneaky", [400., 300.]);
+    join(&mut spectator, "sneaky");
+    assert_silent(&mut player);
+}
+
+#[test]
+fn spectators_are_not_counted_as_players() {
+    let server = start_server();
+    let _player = connect(server.addr());
+    let mut spectator = connect(server.addr());
+    spectator.send(&Message::Spectate).unwrap();
+
+    let mut browser = Browser::with_targets(vec![server.addr()]).unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    let mut players = None;
+
+    while players != Some(1) && Instant::now() < deadline {
+        browser.poll();
+        players = browser.servers().first().map(|(_, info)| info.players);
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    assert_eq!(players, Some(1));
+}
+
+#[test]
+fn server_answers_discovery_probes() {
+    let mut server = Server::bind("127.0.0.1:0").unwrap();
+    server.set_name("basement".to_string());
+    let server = server.spawn().unwrap();
+    let _client = connect(server.addr());
+
+    let mut browser = Browser::with_targets(vec![server.addr()]).unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+
+    while browser.servers().is_empty() && Instant::now() < deadline {
+        browser.poll();
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    let expected = ServerInfo { name: "basement".to_string(), version: PROTOCOL_VERSION, players: 1 };
+    assert_eq!(browser.servers(), vec![(server.addr(), expected)]);
+}
+
+#[test]
+fn hosted_game_is_joined_over_loopback() {
+    let hosted = server::host(0, "Fridge's game".to_string()).unwrap();
+    let addr = hosted.loopback_addr();
+    let mut first = connect(addr);
+    let mut second = connect(addr);
+
+    spawn(&mut first, "first", [400., 300.]);
+    assert_eq!(receive(&mut second, 1).len(), 1);
+
+    // the game ends with its host
+    drop(hosted);
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn udp_is_negotiated() {
+    let server = start_server();
+    let client = connect(server.addr());
+
+    assert!(client.has_feature(FEATURE_UDP));
+}
+
+#[test]
+fn udp_is_used_once_the_server_answers() {
+    let server = start_server();
+    let mut client = connect(server.addr());
+    let deadline = Instant::now() + TIMEOUT;
+
+    while !client.uses_udp() && Instant::now() < deadline {
+        for result in client.poll_messages() {
+            result.expect("valid message");
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    assert!(client.uses_udp());
+}
+
+#[test]
+fn unanswered_udp_falls_back_to_tcp() {
+    // negotiates udp, but nothing listens for datagrams as if a firewall dropped them
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let welcome = Welcome { version: PROTOCOL_VERSION, features: FEATURE_UDP, accepted: true, token: 1 };
+
+    let server = thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        socket.read_exact(&mut [0u8; HELLO_LEN]).unwrap();
+        socket.write_all(&handshake::encode_welcome(&welcome)).unwrap();
+
+        let mut stream = MessageStream::new(socket).unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+
+        while Instant::now() < deadline {
+            for result in stream.poll_messages() {
+                if let Ok(message @ Message::UpdatePos { .. }) = result {
+                    return Some(message);
+                }
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        None
+    });
+
+    let mut client = connect(addr);
+    assert!(!client.uses_udp());
+    client.send_update_pos_event(1, [410., 300.], 0., [0., 0.]).unwrap();
+
+    let update = server.join().unwrap();
+    assert_eq!(update, Some(Message::UpdatePos { token: 1, seq: 1, pos: [410., 300.], rotation: 0., velocity: [0., 0.] }));
+}
+
+#[test]
+fn stale_datagrams_are_dropped() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    let mut history = SnapshotHistory::new();
+
+    spawn(&mut first, "first", [400., 300.]);
+    watch_world(&mut second, &mut history, |world| world.entities.contains_key(&first.token));
+
+    // datagrams of the first client, where 101 is overtaken by 102 on the way
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let send = |seq: u32, x: f64| {
+        let update = Message::UpdatePos { token: first.token, seq, pos: [400. + x, 300.], rotation: 0., velocity: [0., 0.] };
+        udp.send_to(&protocol::encode_datagram(first.token, seq, Some(&update)), server.addr()).unwrap();
+    };
+
+    send(100, 1.);
+    send(102, 3.);
+    watch_world(&mut second, &mut history, |world| position_in(world, first.token) == Some([403., 300.]));
+
+    send(101, 2.);
+    send(103, 4.);
+    watch_world(&mut second, &mut history, |world| {
+        assert!(position_in(world, first.token) != Some([402., 300.]), "stale update applied");
+        position_in(world, first.token) == Some([404., 300.])
+    });
+}
+
+#[test]
+fn late_client_receives_spawned_players() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    let mut history = SnapshotHistory::new();
+
+    spawn(&mut first, "first", [400., 300.]);
+    first.send_update_pos_event(1, [405., 305.], 0., [0., 0.]).unwrap();
+    // once the update is in a snapshot the server knows the latest position
+    watch_world(&mut second, &mut history, |world| position_in(world, first.token) == Some([405., 305.]));
+
+    let mut late = connect(server.addr());
+
+    let expected = Message::Spawn { token: first.token, name: "first".to_string(), pos: [405., 305.], color: [1., 0., 0., 1.] };
+    assert_eq!(receive(&mut late, 1), vec![expected]);
+}
+
+#[test]
+fn disconnected_player_is_forgotten() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    spawn(&mut first, "first", [400., 300.]);
+    assert_eq!(receive(&mut second, 1).len(), 1);
+
+    let token = first.token;
+    drop(first);
+    assert_eq!(receive(&mut second, 1), vec![Message::Despawn { token }]);
+
+    let mut late = connect(server.addr());
+    assert_silent(&mut late);
+
+    // the remaining clients keep talking to each other
+    spawn(&mut second, "second", [400., 300.]);
+    assert_eq!(receive(&mut late, 1).len(), 1);
+}
+
+#[test]
+fn leaving_player_is_despawned_everywhere() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+    let mut third = connect(server.addr());
+
+    spawn(&mut first, "first", [400., 300.]);
+    assert_eq!(receive(&mut second, 1).len(), 1);
+    assert_eq!(receive(&mut third, 1).len(), 1);
+
+    first.send(&Message::Despawn { token: first.token }).unwrap();
+
+    let expected = vec![Message::Despawn { token: first.token }];
+    assert_eq!(receive(&mut second, 1), expected);
+    assert_eq!(receive(&mut third, 1), expected);
+    assert_silent(&mut first);
+}
+
+#[test]
+fn client_without_player_leaves_silently() {
+    let server = start_server();
+    let first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    drop(first);
+
+    assert_silent(&mut second);
+}
+
+#[test]
+fn stopped_server_disconnects_clients() {
+    let mut server = start_server();
+    let mut client = connect(server.addr());
+
+    server.stop();
+
+    let deadline = Instant::now() + TIMEOUT;
+    let mut error = None;
+
+    while error.is_none() && Instant::now() < deadline {
+        error = client.poll_messages().into_iter().filter_map(|result| result.err()).next();
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    assert_eq!(error, Some(ProtocolError::Disconnected));
+    assert!(ProtocolError::Disconnected.is_fatal());
+}
+
+// polls until the server names a map
+fn wait_map(connection: &mut Connection) -> String {
+    let deadline = Instant::now() + TIMEOUT;
+
+    while Instant::now() < deadline {
+        for result in connection.poll_messages() {
+            if let Message::Map { name } = result.expect("valid message") {
+                return name;
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    panic!("no map");
+}
+
+// polls until the server closes the connection, returns the reason it told
+fn wait_disconnect(connection: &mut Connection) -> Option<String> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut reason = None;
+
+    while Instant::now() < deadline {
+        for result in connection.poll_messages() {
+            match result {
+                Ok(Message::Disconnect { reason: text }) => reason = Some(text),
+                Err(ProtocolError::Disconnected) => return reason,
+                _ => ()
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    panic!("still connected");
+}
+
+#[test]
+fn clients_are_told_the_map() {
+    let server = start_server();
+    let mut client = connect(server.addr());
+
+    assert_eq!(wait_map(&mut client), world::DEFAULT_MAP);
+}
+
+#[test]
+fn admin_lists_clients() {
+    let server = start_server();
+    let mut player = connect(server.addr());
+    let mut member = connect(server.addr());
+
+    spawn(&mut player, "first", [400., 300.]);
+    join(&mut member, "second");
+    watch_lobby(&mut member, |_, players| players.len() == 1);
+
+    let list = server.execute(Command::List).unwrap();
+    assert!(list.starts_with("map default, 2 clients"), "{}", list);
+    assert!(list.contains(&format!("{:>4}  ", player.token)), "{}", list);
+    assert!(list.contains("playing as first"), "{}", list);
+    assert!(list.contains("in the lobby as second"), "{}", list);
+}
+
+#[test]
+fn kicked_client_is_gone_for_good() {
+    let server = start_server();
+    let mut kicked = connect(server.addr());
+    let mut other = connect(server.addr());
+
+    spawn(&mut kicked, "kicked", [400., 300.]);
+    assert_eq!(receive(&mut other, 1).len(), 1);
+    let session = wait_session(&mut kicked);
+
+    let result = server.execute(Command::Kick("kicked".to_string())).unwrap();
+    assert!(result.contains(&session.token.to_string()), "{}", result);
+
+    assert_eq!(wait_disconnect(&mut kicked), Some("kicked by the server".to_string()));
+    assert_eq!(receive(&mut other, 1), vec![Message::Despawn { token: session.token }]);
+
+    let mut again = connect(server.addr());
+    assert_eq!(again.resume(&session), Ok(false));
+
+    assert!(server.execute(Command::Kick("nobody".to_string())).unwrap().contains("no client"));
+}
+
+#[test]
+fn admin_notice_reaches_everyone() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    server.execute(Command::Say("restart in 5 minutes".to_string())).unwrap();
+
+    let expected = vec![Message::Notice { text: "restart in 5 minutes".to_string() }];
+    assert_eq!(receive(&mut first, 1), expected);
+    assert_eq!(receive(&mut second, 1), expected);
+}
+
+#[test]
+fn map_change_removes_players() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+    let mut history = SnapshotHistory::new();
+
+    assert_eq!(wait_map(&mut first), world::DEFAULT_MAP);
+    spawn(&mut first, "first", [400., 300.]);
+    watch_world(&mut second, &mut history, |world| world.entities.contains_key(&first.token));
+
+    assert!(server.execute(Command::Map("nowhere".to_string())).unwrap().contains("unknown map"));
+    assert!(server.execute(Command::Map(world::DEFAULT_MAP.to_string())).unwrap().contains("already on map"));
+    // the player is kept and still moves
+    first.send_update_pos_event(1, [410., 300.], 0., [0., 0.]).unwrap();
+    watch_world(&mut second, &mut history, |world| position_in(world, first.token) == Some([410., 300.]));
+
+    server.execute(Command::Map("arena".to_string())).unwrap();
+
+    assert_eq!(wait_map(&mut first), "arena");
+    assert_eq!(wait_map(&mut second), "arena");
+    watch_world(&mut second, &mut history, |world| world.entities.is_empty());
+
+    // and newcomers are told the new one
+    let mut third = connect(server.addr());
+    assert_eq!(wait_map(&mut third), "arena");
+}
+
+#[test]
+fn shutdown_tells_clients_why() {
+    let server = start_server();
+    let mut client = connect(server.addr());
+
+    server.execute(Command::Shutdown).unwrap();
+
+    assert_eq!(wait_disconnect(&mut client), Some("the server shut down".to_string()));
+    assert_eq!(server.execute(Command::List), None);
+}
+
+// polls until the server tells the session secret
+fn wait_session(connection: &mut Connection) -> Session {
+    let deadline = Instant::now() + TIMEOUT;
+
+    while Instant::now() < deadline {
+        if let Some(session) = connection.session() {
+            return session;
+        }
+
+        for result in connection.poll_messages() {
+            result.expect("valid message");
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    panic!("no session");
+}
+
+#[test]
+fn lost_session_is_resumed() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let mut second = connect(server.addr());
+
+    spawn(&mut first, "first", [400., 300.]);
+    assert_eq!(receive(&mut second, 1).len(), 1);
+    let session = wait_session(&mut first);
+    drop(first);
+
+    let mut again = connect(server.addr());
+    assert!(again.token != session.token);
+    assert_eq!(again.resume(&session), Ok(true));
+    assert_eq!(again.token, session.token);
+
+    // the player never left for the others
+    thread::sleep(SESSION_GRACE);
+    assert_silent(&mut second);
+
+    // and it is still controlled by the resumed client
+    let mut history = SnapshotHistory::new();
+    again.send_update_pos_event(1, [430., 340.], 0., [0., 0.]).unwrap();
+    watch_world(&mut second, &mut history, |world| position_in(world, session.token) == Some([430., 340.]));
+}
+
+#[test]
+fn live_connection_is_replaced_by_resume() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let session = wait_session(&mut first);
+
+    let mut again = connect(server.addr());
+    assert_eq!(again.resume(&session), Ok(true));
+
+    let deadline = Instant::now() + TIMEOUT;
+    let mut error = None;
+
+    while error.is_none() && Instant::now() < deadline {
+        error = first.poll_messages().into_iter().filter_map(|result| result.err()).next();
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    assert_eq!(error, Some(ProtocolError::Disconnected));
+}
+
+#[test]
+fn wrong_secret_does_not_resume() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let session = wait_session(&mut first);
+    drop(first);
+
+    let mut again = connect(server.addr());
+    let token = again.token;
+    let forged = Session { token: session.token, secret: session.secret.wrapping_add(1) };
+
+    assert_eq!(again.resume(&forged), Ok(false));
+    assert_eq!(again.token, token);
+}
+
+#[test]
+fn expired_session_does_not_resume() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let session = wait_session(&mut first);
+    drop(first);
+
+    thread::sleep(SESSION_GRACE * 2);
+
+    let mut again = connect(server.addr());
+    assert_eq!(again.resume(&session), Ok(false));
+}
+
+#[test]
+fn connector_resumes_session() {
+    let server = start_server();
+    let mut first = connect(server.addr());
+    let session = wait_session(&mut first);
+    drop(first);
+
+    let mut connector = Connector::resume(server.addr().to_string(), &NetConfig::default(), session.clone());
+
+    match finish(&mut connector) {
+        (_, ConnectEvent::Connected(ref connection)) => assert_eq!(connection.token, session.token),
+        _ => panic!("not connected")
+    }
+}
+
+// a one-shot fake server that answers any client with `reply`
+fn fake_server(reply: Vec<u8>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        socket.write_all(&reply).unwrap();
+        // keep the socket open until the client hangs up, ignoring whatever it sends
+        let mut buf = [0u8; 64];
+        while let Ok(len) = socket.read(&mut buf) {
+            if len == 0 {
+                break;
+            }
+        }
+    });
+
+    addr
+}
+
+#[test]
+fn client_with_other_version_is_rejected() {
+    let server = start_server();
+    let mut socket = TcpStream::connect(server.addr()).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+    socket.write_all(&handshake::encode_hello(&Hello { version: PROTOCOL_VERSION + 1, features: 0 })).unwrap();
+
+    let mut buf = [0u8; WELCOME_LEN];
+    socket.read_exact(&mut buf).unwrap();
+    let welcome = handshake::decode_welcome(&buf).unwrap();
+
+    assert!(!welcome.accepted);
+    assert_eq!(welcome.version, PROTOCOL_VERSION);
+    // and the server hangs up
+    assert_eq!(socket.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn foreign_client_is_dropped() {
+    let server = start_server();
+    let mut socket = TcpStream::connect(server.addr()).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+    socket.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+    // unread bytes turn the close into a reset
+    match socket.read(&mut [0u8; 64]) {
+        Ok(0) => (),
+        Err(ref e) if e.kind() == ErrorKind::ConnectionReset => (),
+        other => panic!("unexpected {:?}", other)
+    }
+}
+
+#[test]
+fn foreign_server_is_detected() {
+    let addr = fake_server(b"HTTP/1.1 400 Bad Request\r\n\r\n".to_vec());
+
+    match TcpStream::connect(addr).map(Connection::new) {
+        Ok(Err(ProtocolError::BadMagic(_))) => (),
+        other => panic!("unexpected {:?}", other.map(|result| result.map(|connection| connection.token)))
+    }
+}
+
+#[test]
+fn server_with_other_version_is_detected() {
+    let welcome = Welcome { version: PROTOCOL_VERSION + 1, features: 0, accepted: true, token: 1 };
+    let addr = fake_server(handshake::encode_welcome(&welcome));
+
+    match TcpStream::connect(addr).map(Connection::new) {
+        Ok(Err(err)) => assert_eq!(err, ProtocolError::VersionMismatch { local: PROTOCOL_VERSION, remote: PROTOCOL_VERSION + 1 }),
+        other => panic!("unexpected {:?}", other.map(|result| result.map(|connection| connection.token)))
+    }
+}
+
+fn quick_heartbeat() -> NetConfig {
+    NetConfig {
+        ping_interval: Duration::from_millis(20),
+        timeout: Duration::from_millis(300),
+        ..NetConfig::default()
+    }
+}
+
+#[test]
+fn server_answers_pings() {
+    let server = start_server();
+    let mut client = Connection::with_config(TcpStream::connect(server.addr()).unwrap(), &quick_heartbeat()).unwrap();
+
+    let deadline = Instant::now() + TIMEOUT;
+    while client.rtt().is_none() && Instant::now() < deadline {
+        // pongs are consumed by the connection itself
+        assert!(client.poll_messages().iter().all(|result| result.is_ok()));
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    assert!(client.rtt().unwrap() < TIMEOUT);
+    // nothing is lost on loopback, whether udp got through by now or not
+    assert_eq!(client.packet_loss().unwrap_or(0.), 0.);
+
+    // a server that keeps answering never times out
+    thread::sleep(Duration::from_millis(400));
+    assert!(client.poll_messages().iter().all(|result| result.is_ok()));
+}
+
+fn simulated(conditions: NetConditions) -> NetConfig {
+    NetConfig { simulation: Some(conditions), ..quick_heartbeat() }
+}
+
+#[test]
+fn simulated_latency_delays_both_ways() {
+    let server = start_server();
+    let latency = Duration::from_millis(100);
+    let config = simulated(NetConditions { latency, ..NetConditions::default() });
+    let mut client = Connection::with_config(TcpStream::connect(server.addr()).unwrap(), &config).unwrap();
+
+    let deadline = Instant::now() + TIMEOUT;
+    while client.rtt().is_none() && Instant::now() < deadline {
+        assert!(client.poll_messages().iter().all(|result| result.is_ok()));
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    assert!(client.rtt().unwrap() >= latency * 2);
+}
+
+#[test]
+fn simulated_loss_spares_reliable_messages() {
+    let server = start_server();
+    let conditions = NetConditions { latency: Duration::from_millis(20), loss: 1., ..NetConditions::default() };
+    let lossy = NetConfig { simulation: Some(conditions), ..NetConfig::default() };
+    let mut first = Connection::with_config(TcpStream::connect(server.addr()).unwrap(), &lossy).unwrap();
+    let mut second = Connection::with_config(TcpStream::connect(server.addr()).unwrap(), &lossy).unwrap();
+
+    spawn(&mut first, "first", [400., 300.]);
+    first.send_chat("still there".to_string()).unwrap();
+
+    // outgoing messages leave on polls once their delay is over
+    thread::sleep(Duration::from_millis(50));
+    assert!(first.poll_messages().iter().all(|result| result.is_ok()));
+
+    let messages = receive(&mut second, 2);
+    assert!(matches!(messages[0], Message::Spawn { .. }), "unexpected {:?}", messages);
+    assert_eq!(messages[1], Message::Chat { token: first.token, text: "still there".to_string() });
+}
+
+#[test]
+fn simulated_disconnect_is_reported_once() {
+    let mut server = start_server();
+    let latency = Duration::from_millis(100);
+    let config = NetConfig { simulation: Some(NetConditions { latency, ..NetConditions::default() }), ..NetConfig::default() };
+    let mut client = Connection::with_config(TcpStream::connect(server.addr()).unwrap(), &config).unwrap();
+
+    server.stop();
+    thread::sleep(Duration::from_millis(20));
+
+    // each of these reads the closed stream again while the first error is still on its way
+    for _ in 0..5 {
+        assert!(client.poll_messages().iter().all(|result| result.is_ok()));
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    thread::sleep(latency * 2);
+
+    let results = client.poll_messages();
+    let fatal = results.iter().filter(|result| matches!(**result, Err(ref err) if err.is_fatal())).count();
+    assert_eq!(fatal, 1, "unexpected {:?}", results);
+    assert_eq!(results.last(), Some(&Err(ProtocolError::Disconnected)));
+}
+
+#[test]
+fn silent_server_times_out() {
+    let addr = fake_server(handshake::encode_welcome(&Welcome::accept(1, 0)));
+    let mut client = Connection::with_config(TcpStream::connect(addr).unwrap(), &quick_heartbeat()).unwrap();
+
+    let deadline = Instant::now() + TIMEOUT;
+    let mut error = None;
+
+    while error.is_none() && Instant::now() < deadline {
+        error = client.poll_messages().into_iter().filter_map(|result| result.err()).next();
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    assert_eq!(error, Some(ProtocolError::TimedOut));
+    assert!(client.silence() >= Duration::from_millis(300));
+}
+
+// collects connector events until the attempt is over
+fn finish(connector: &mut Connector) -> (Vec<u32>, ConnectEvent) {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut attempts = vec![];
+
+    while Instant::now() < deadline {
+        match connector.poll() {
+            Some(ConnectEvent::Attempt(attempt)) => attempts.push(attempt),
+            Some(event) => return (attempts, event),
+            None => thread::sleep(POLL_INTERVAL)
+        }
+    }
+
+    panic!("connector did not finish, attempts: {:?}", attempts);
+}
+
+fn quick_retries() -> NetConfig {
+    NetConfig {
+        connect_timeout: Duration::from_millis(200),
+        connect_attempts: 3,
+        retry_backoff: Duration::from_millis(10),
+        ..NetConfig::default()
+    }
+}
+
+#[test]
+fn connector_connects_in_background() {
+    let server = start_server();
+    let mut connector = Connector::start(server.addr().to_string(), &quick_retries());
+
+    match finish(&mut connector) {
+        (ref attempts, ConnectEvent::Connected(ref connection)) => {
+            assert_eq!(*attempts, vec![1]);
+            assert_eq!(connection.addr, server.addr());
+        },
+        _ => panic!("not connected")
+    }
+}
+
+#[test]
+fn connector_retries_and_gives_up() {
+    // nobody listens on a port that was just released
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut connector = Connector::start(addr.to_string(), &quick_retries());
+
+    match finish(&mut connector) {
+        (ref attempts, ConnectEvent::Failed(ProtocolError::Io(_))) => assert_eq!(*attempts, vec![1, 2, 3]),
+        _ => panic!("unexpected result")
+    }
+}
+
+#[test]
+fn connector_does_not_retry_incompatible_server() {
+    let welcome = Welcome { version: PROTOCOL_VERSION + 1, features: 0, accepted: true, token: 1 };
+    let addr = fake_server(handshake::encode_welcome(&welcome));
+    let mut connector = Connector::start(addr.to_string(), &quick_retries());
+
+    match finish(&mut connector) {
+        (ref attempts, ConnectEvent::Failed(ProtocolError::VersionMismatch { .. })) => assert_eq!(*attempts, vec![1]),
+        _ => panic!("unexpected result")
+    }
+}
+
+#[test]
+fn connector_reports_unresolvable_host() {
+    let mut connector = Connector::start("not an address".to_string(), &quick_retries());
+
+    match finish(&mut connector) {
+        (_, ConnectEvent::Failed(_)) => (),
+        _ => panic!("unexpected result")
+    }
+}